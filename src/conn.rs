@@ -1,40 +1,111 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt;
+use std::future::Future;
+use std::num::NonZeroU64;
+use std::sync::{Arc, Weak};
 use std::str::Utf8Error;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
+use async_trait::async_trait;
 use bitflags::bitflags;
-use hyper::{Body, Client, Request, StatusCode};
-use hyper::client::HttpConnector;
-use hyper_tls::HttpsConnector;
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::stream::{self, Stream};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper::client::connect::Connect;
+use hyper::header::{HeaderName, HeaderValue, USER_AGENT};
 use json::JsonValue;
-use log::debug;
-use tokio::sync::RwLock;
+use log::{debug, warn};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use url::Url;
 
-use crate::{AciObject, AciObjectError};
+use crate::{ACI_TIMESTAMP_FORMAT, AciObject, AciObjectError};
 use crate::auth::{ApicAuthenticator, ApicAuthenticatorData};
+use crate::filter::FilterExpression;
+use crate::proxy::ProxyOptions;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::session::{ApicSession, SessionCacheError};
+use crate::tls::{ConnectorSettings, TlsError, TlsOptions, TlsStream};
 
 
 /// The maximum duration before a session times out where a refresh of the login session is
 /// recommended.
 const REFRESH_BEFORE_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// The timeout applied to requests by connections created using a builder unless specified
+/// otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The duration to wait before the keep-alive task tries again after failing to refresh the
+/// session and to log in again.
+const KEEP_ALIVE_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+
+/// A means of sending HTTP requests to an APIC.
+///
+/// This trait is object-safe and implemented for every hyper `Client`, so a client with a custom
+/// connector can be passed to `ApicConnectionBuilder::transport`. Implementing it directly allows
+/// intercepting requests, e.g. to answer them without a network in tests.
+#[async_trait]
+pub trait HttpTransport: fmt::Debug + Send + Sync {
+    /// Sends the given request and returns the response.
+    async fn request(&self, request: Request<Body>) -> Result<Response<Body>, hyper::Error>;
+}
+#[async_trait]
+impl<C> HttpTransport for Client<C, Body>
+        where C: 'static + Clone + Connect + Send + Sync {
+    async fn request(&self, request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        Client::request(self, request).await
+    }
+}
+
+/// Returns the transport used by default: a hyper client supporting HTTP and HTTPS, verifying
+/// certificates against the system's trusted roots.
+pub fn default_transport() -> Arc<dyn HttpTransport> {
+    TlsOptions::new()
+        .transport()
+        .expect("failed to initialize TLS")
+}
+
+/// A transport adding default headers to each request that does not already contain them.
+#[derive(Debug)]
+struct DefaultHeadersTransport {
+    inner: Arc<dyn HttpTransport>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+#[async_trait]
+impl HttpTransport for DefaultHeadersTransport {
+    async fn request(&self, mut request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let present: HashSet<HeaderName> = request.headers()
+            .keys()
+            .cloned()
+            .collect();
+        for (name, value) in &self.headers {
+            if !present.contains(name) {
+                request.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        self.inner.request(request).await
+    }
+}
+
 
 /// Performs a JSON request against an APIC-like server.
 ///
 /// This is a very low-level operation. Unless you are implementing a custom ApicAuthenticator, you
 /// probably want to use the associated functions of ApicConnection.
-pub async fn perform_json_request<C>(
-    client: &Client<C, Body>,
+pub async fn perform_json_request(
+    client: &dyn HttpTransport,
     uri: Url,
     method: &str,
     headers: &HashMap<String, String>,
     body: Option<JsonValue>,
     timeout: Duration,
-) -> Result<JsonValue, ApicCommError>
-        where C: 'static + Clone + hyper::client::connect::Connect + Send + Sync {
+) -> Result<JsonValue, ApicCommError> {
     debug!("{} {}", method, uri);
 
     let body_bytes: Option<Vec<u8>> = body
@@ -53,7 +124,7 @@ pub async fn perform_json_request<C>(
         bldr.body(Body::empty())
     };
     let req = req_res
-        .map_err(|e| ApicCommError::ErrorAssemblingRequest(e))?;
+        .map_err(ApicCommError::ErrorAssemblingRequest)?;
 
     let response_or_timeout = tokio::time::timeout(
         timeout,
@@ -68,17 +139,25 @@ pub async fn perform_json_request<C>(
     let (response_parts, response_body) = response.into_parts();
     let response_bytes = hyper::body::to_bytes(response_body)
         .await
-        .map_err(|e| ApicCommError::ErrorObtainingResponse(e))?;
-    let response_str = std::str::from_utf8(&response_bytes)
-        .map_err(|e| ApicCommError::InvalidUtf8(e, response_bytes.clone()))?;
-    let response_json = json::parse(response_str)
-        .map_err(|e| ApicCommError::InvalidJson(e, String::from(response_str)))?;
+        .map_err(ApicCommError::ErrorObtainingResponse)?;
 
     if response_parts.status != StatusCode::OK {
-        return Err(ApicCommError::ErrorResponse(response_json, response_parts).into());
+        // error pages (e.g. from a load balancer) are not necessarily JSON
+        let response_text = String::from_utf8_lossy(&response_bytes);
+        let response_json = match json::parse(&response_text) {
+            Ok(j) => j,
+            Err(_) => JsonValue::String(response_text.into_owned()),
+        };
+        if let Some(apic_error) = ApicError::from_response(&response_json, response_parts.status) {
+            return Err(ApicCommError::ApicError(apic_error, Box::new(response_parts)));
+        }
+        return Err(ApicCommError::ErrorResponse(response_json, Box::new(response_parts)));
     }
 
-    Ok(response_json)
+    let response_str = std::str::from_utf8(&response_bytes)
+        .map_err(|e| ApicCommError::InvalidUtf8(e, response_bytes.clone()))?;
+    json::parse(response_str)
+        .map_err(|e| ApicCommError::InvalidJson(e, String::from(response_str)))
 }
 
 /// Converts a JSON value returned by the APIC into a vector of ACI objects.
@@ -99,6 +178,162 @@ pub fn json_to_aci_objects(body: JsonValue) -> Result<Vec<AciObject>, AciObjectE
     Ok(ret)
 }
 
+/// Converts a JSON value returned by the APIC into a query result, retaining the response
+/// metadata.
+///
+/// This JSON value is an object with an `imdata` key containing a list of single ACI objects and
+/// additional keys such as `totalCount`.
+pub fn json_to_query_result(body: JsonValue) -> Result<QueryResult, AciObjectError> {
+    let total_count = {
+        let tc = &body["totalCount"];
+        tc.as_u64()
+            .or_else(|| tc.as_str().and_then(|tcs| tcs.parse().ok()))
+    };
+
+    let mut metadata = HashMap::new();
+    for (key, value) in body.entries() {
+        if key != "imdata" {
+            metadata.insert(String::from(key), value.clone());
+        }
+    }
+
+    let objects = json_to_aci_objects(body)?;
+    Ok(QueryResult {
+        objects,
+        total_count,
+        metadata,
+    })
+}
+
+
+/// The result of a query, consisting of the returned managed objects and the metadata returned by
+/// the APIC alongside them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryResult {
+    objects: Vec<AciObject>,
+    total_count: Option<u64>,
+    metadata: HashMap<String, JsonValue>,
+}
+impl QueryResult {
+    /// Returns a reference to the vector of objects returned by the query.
+    pub fn objects(&self) -> &Vec<AciObject> {
+        &self.objects
+    }
+
+    /// Consumes this QueryResult and returns the vector of objects returned by the query.
+    pub fn into_objects(self) -> Vec<AciObject> {
+        self.objects
+    }
+
+    /// Returns the total number of objects matching the query (`totalCount`), if the APIC has
+    /// provided it.
+    ///
+    /// If the query is paged, this is the number of objects across all pages.
+    pub fn total_count(&self) -> Option<u64> {
+        self.total_count
+    }
+
+    /// Returns whether the objects returned by the query constitute all the objects matching the
+    /// query, i.e. whether the result has not been truncated (e.g. by paging).
+    ///
+    /// If the APIC has not provided a total count, the result is assumed to be complete.
+    pub fn is_complete(&self) -> bool {
+        match self.total_count {
+            Some(tc) => self.objects.len() as u64 >= tc,
+            None => true,
+        }
+    }
+
+    /// Returns the count of matching objects returned by a query with
+    /// `ResponseSubtreeInclude::COUNT`, i.e. the `count` attribute of the returned `moCount`
+    /// object, if one has been returned.
+    pub fn mo_count(&self) -> Option<u64> {
+        self.objects.iter()
+            .find(|o| o.class_name() == "moCount")
+            .and_then(|o| o.attributes().get("count"))
+            .and_then(|c| c.parse().ok())
+    }
+
+    /// Returns the ID of the subscription created by the query, if any.
+    pub fn subscription_id(&self) -> Option<&str> {
+        self.metadata.get("subscriptionId")
+            .and_then(|sid| sid.as_str())
+    }
+
+    /// Returns a reference to the top-level entries of the APIC response other than `imdata`,
+    /// e.g. `totalCount`.
+    pub fn metadata(&self) -> &HashMap<String, JsonValue> {
+        &self.metadata
+    }
+}
+
+
+/// The state of a stream that fetches objects page by page.
+struct PagedStreamState<F> {
+    fetch_page: F,
+    page_size: u64,
+    next_page: u64,
+    buffer: VecDeque<AciObject>,
+    fetched: u64,
+    done: bool,
+}
+
+/// Creates a stream that lazily fetches pages of objects using `fetch_page` and yields the objects
+/// one by one.
+///
+/// `fetch_page` is called with the zero-based index of the page to fetch and returns the result of
+/// the query for that page. Fetching stops once the total number of objects has been fetched, a
+/// page shorter than `page_size` has been returned, or an error has occurred.
+pub(crate) fn paged_stream<F, Fut>(
+    page_size: NonZeroU64,
+    fetch_page: F,
+) -> impl Stream<Item = Result<AciObject, ApicCommError>>
+        where
+            F: FnMut(u64) -> Fut,
+            Fut: Future<Output = Result<QueryResult, ApicCommError>> {
+    let state = PagedStreamState {
+        fetch_page,
+        page_size: page_size.get(),
+        next_page: 0,
+        buffer: VecDeque::new(),
+        fetched: 0,
+        done: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(obj) = state.buffer.pop_front() {
+                return Some((Ok(obj), state));
+            }
+            if state.done {
+                return None;
+            }
+
+            match (state.fetch_page)(state.next_page).await {
+                Ok(result) => {
+                    let total_count = result.total_count();
+                    let objects = result.into_objects();
+                    let page_length = objects.len() as u64;
+                    state.next_page += 1;
+                    state.fetched += page_length;
+                    if page_length < state.page_size {
+                        state.done = true;
+                    }
+                    if let Some(tc) = total_count {
+                        if state.fetched >= tc {
+                            state.done = true;
+                        }
+                    }
+                    state.buffer.extend(objects);
+                },
+                Err(e) => {
+                    state.done = true;
+                    return Some((Err(e), state));
+                },
+            }
+        }
+    })
+}
+
 
 /// Allows an object to return the corresponding REST API query key and value.
 trait RestQueryParam {
@@ -283,15 +518,104 @@ impl RestQueryParam for ResponsePropertyInclude {
 }
 
 
+/// Defines the direction in which results are sorted.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SortOrder {
+    /// Sort from the lowest to the highest value.
+    Ascending,
+
+    /// Sort from the highest to the lowest value.
+    Descending,
+}
+impl SortOrder {
+    /// Returns the value to pass as part of a GET argument to the REST API.
+    fn rest_value(&self) -> &'static str {
+        match &self {
+            SortOrder::Ascending => "asc",
+            SortOrder::Descending => "desc",
+        }
+    }
+}
+
+
+/// Defines the window of time in which the returned objects must have been created.
+///
+/// This is mostly useful when querying records classes such as `faultRecord`, `eventRecord`,
+/// `aaaModLR` or `healthRecord`.
+///
+/// The windows of the last 24 hours and the last week are passed to the APIC as the `time-range`
+/// query parameter, so they are resolved using the APIC's clock. The APIC does not accept other
+/// relative windows or arbitrary instants there; all other time ranges are expressed as a filter
+/// on the `created` property, which must be qualified with the queried class.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TimeRange {
+    /// Objects created between the two instants (inclusive).
+    Between(DateTime<Utc>, DateTime<Utc>),
+
+    /// Objects created at or after the given instant.
+    Since(DateTime<Utc>),
+
+    /// Objects created at or before the given instant.
+    Until(DateTime<Utc>),
+
+    /// Objects created within the given duration before the query is performed.
+    ///
+    /// Durations reaching back before the Unix epoch are clamped to the epoch.
+    Last(Duration),
+}
+impl TimeRange {
+    /// Returns the value of the `time-range` query parameter corresponding to this time range, if
+    /// the APIC supports it.
+    fn rest_value(&self) -> Option<&'static str> {
+        match self {
+            TimeRange::Last(duration) if *duration == Duration::from_secs(24*60*60) => Some("24h"),
+            TimeRange::Last(duration) if *duration == Duration::from_secs(7*24*60*60) => Some("1week"),
+            _ => None,
+        }
+    }
+
+    /// Returns a filter expression restricting the `created` property of the given class to this
+    /// time range, relative to the given current instant.
+    fn filter_expression(&self, class_name: &str, now: DateTime<Utc>) -> FilterExpression {
+        let property = format!("{}.created", class_name);
+        let format_ts = |ts: &DateTime<Utc>| ts.format(ACI_TIMESTAMP_FORMAT).to_string();
+        match &self {
+            TimeRange::Between(start, end)
+                => FilterExpression::between(property, format_ts(start), format_ts(end)),
+            TimeRange::Since(start)
+                => FilterExpression::greater_or_equal(property, format_ts(start)),
+            TimeRange::Until(end)
+                => FilterExpression::less_or_equal(property, format_ts(end)),
+            TimeRange::Last(duration) => {
+                let epoch = Utc.timestamp(0, 0);
+                let start = chrono::Duration::from_std(*duration)
+                    .ok()
+                    .and_then(|d| now.checked_sub_signed(d))
+                    .map_or(epoch, |start| start.max(epoch));
+                FilterExpression::greater_or_equal(property, format_ts(&start))
+            },
+        }
+    }
+}
+
+
 /// Allows query settings to be set before a query is performed.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct QuerySettings {
     query_target: QueryTarget,
     query_target_filter: Option<String>,
+    target_subtree_classes: Option<HashSet<String>>,
     response_subtree: ResponseSubtree,
     response_subtree_classes: Option<HashSet<String>>,
+    response_subtree_filter: Option<String>,
     response_subtree_include: Option<ResponseSubtreeInclude>,
     response_property_include: ResponsePropertyInclude,
+    page_size: Option<NonZeroU64>,
+    page: Option<u64>,
+    order_by: Vec<(String, SortOrder)>,
+    created_within: Option<(String, TimeRange)>,
+    subscription: bool,
+    retry_policy: Option<RetryPolicy>,
 }
 impl QuerySettings {
     /// Creates a new QuerySettings instance with common defaults.
@@ -299,10 +623,18 @@ impl QuerySettings {
         QuerySettings {
             query_target: QueryTarget::ConsiderSubtree,
             query_target_filter: None,
+            target_subtree_classes: None,
             response_subtree: ResponseSubtree::ReturnFull,
             response_subtree_classes: None,
+            response_subtree_filter: None,
             response_subtree_include: None,
             response_property_include: ResponsePropertyInclude::All,
+            page_size: None,
+            page: None,
+            order_by: Vec::new(),
+            created_within: None,
+            subscription: false,
+            retry_policy: None,
         }
     }
 
@@ -318,12 +650,38 @@ impl QuerySettings {
         self
     }
 
+    /// Sets the target filter of this query to the given filter expression and returns the
+    /// QuerySettings object.
+    pub fn query_target_filter_expression(mut self, query_target_filter: &FilterExpression) -> Self {
+        self.query_target_filter = Some(query_target_filter.to_string());
+        self
+    }
+
     /// Unsets the target filter of this query and returns the QuerySettings object.
     pub fn query_target_filter_any(mut self) -> Self {
         self.query_target_filter = None;
         self
     }
 
+    /// Sets which classes of objects within the target subtree to consider and returns the
+    /// QuerySettings object.
+    pub fn target_subtree_classes<S: AsRef<str>>(mut self, target_subtree_classes: &[S]) -> Self {
+        self.target_subtree_classes = Some(
+            target_subtree_classes
+                .iter()
+                .map(|s| String::from(s.as_ref()))
+                .collect()
+        );
+        self
+    }
+
+    /// Sets that objects of all classes within the target subtree are to be considered and returns
+    /// the QuerySettings object.
+    pub fn target_subtree_classes_all(mut self) -> Self {
+        self.target_subtree_classes = None;
+        self
+    }
+
     /// Sets the form of the response subtree and returns the QuerySettings object.
     pub fn response_subtree(mut self, response_subtree: ResponseSubtree) -> Self {
         self.response_subtree = response_subtree;
@@ -347,6 +705,27 @@ impl QuerySettings {
         self
     }
 
+    /// Sets the filter applied to the objects in the response subtree and returns the
+    /// QuerySettings object.
+    pub fn response_subtree_filter(mut self, response_subtree_filter: &str) -> Self {
+        self.response_subtree_filter = Some(String::from(response_subtree_filter));
+        self
+    }
+
+    /// Sets the filter applied to the objects in the response subtree to the given filter
+    /// expression and returns the QuerySettings object.
+    pub fn response_subtree_filter_expression(mut self, response_subtree_filter: &FilterExpression) -> Self {
+        self.response_subtree_filter = Some(response_subtree_filter.to_string());
+        self
+    }
+
+    /// Unsets the filter applied to the objects in the response subtree and returns the
+    /// QuerySettings object.
+    pub fn response_subtree_filter_any(mut self) -> Self {
+        self.response_subtree_filter = None;
+        self
+    }
+
     /// Sets which subtree objects to return and returns the QuerySettings object.
     pub fn response_subtree_include(mut self, response_subtree_include: ResponseSubtreeInclude) -> Self {
         self.response_subtree_include = Some(response_subtree_include);
@@ -365,13 +744,113 @@ impl QuerySettings {
         self
     }
 
+    /// Sets the page size and the zero-based index of the page to return and returns the
+    /// QuerySettings object.
+    pub fn page(mut self, page_size: NonZeroU64, page: u64) -> Self {
+        self.page_size = Some(page_size);
+        self.page = Some(page);
+        self
+    }
+
+    /// Sets that all results are to be returned at once (without paging) and returns the
+    /// QuerySettings object.
+    pub fn page_all(mut self) -> Self {
+        self.page_size = None;
+        self.page = None;
+        self
+    }
+
+    /// Appends a sort key to this query and returns the QuerySettings object.
+    ///
+    /// The property is specified in the form `className.propertyName`, e.g. `fvTenant.name`.
+    /// Results are sorted by the sort keys in the order in which they have been added.
+    pub fn order_by(mut self, property: &str, sort_order: SortOrder) -> Self {
+        self.order_by.push((String::from(property), sort_order));
+        self
+    }
+
+    /// Removes all sort keys from this query and returns the QuerySettings object.
+    pub fn order_by_none(mut self) -> Self {
+        self.order_by.clear();
+        self
+    }
+
+    /// Restricts this query to objects of the given class whose `created` property lies within the
+    /// given time range and returns the QuerySettings object.
+    ///
+    /// The restriction is combined with the target filter of this query. Relative time ranges are
+    /// resolved when the query settings are converted into query string keys and values. The class
+    /// name qualifies the `created` property in the filter; it is not needed for windows that are
+    /// passed as the `time-range` query parameter (see [`TimeRange`]).
+    pub fn created_within(mut self, class_name: &str, time_range: TimeRange) -> Self {
+        self.created_within = Some((String::from(class_name), time_range));
+        self
+    }
+
+    /// Removes the restriction on the creation time of objects from this query and returns the
+    /// QuerySettings object.
+    pub fn created_any(mut self) -> Self {
+        self.created_within = None;
+        self
+    }
+
+    /// Sets whether the query should create a subscription for changes to the returned objects and
+    /// returns the QuerySettings object.
+    pub(crate) fn subscription(mut self, subscription: bool) -> Self {
+        self.subscription = subscription;
+        self
+    }
+
+    /// Sets the retry policy applied to this query instead of the connection's retry policy and
+    /// returns the QuerySettings object.
+    ///
+    /// The retry policy does not influence the query string.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Sets that the connection's retry policy is applied to this query and returns the
+    /// QuerySettings object.
+    pub fn retry_policy_default(mut self) -> Self {
+        self.retry_policy = None;
+        self
+    }
+
     /// Convert these query settings into a HashMap of ACI query string keys and values.
     pub fn to_aci_keys_values(self) -> HashMap<String, String> {
         let mut keys_values = HashMap::new();
 
         keys_values.insert(self.query_target.rest_key(), self.query_target.rest_value());
-        if let Some(qtf) = self.query_target_filter {
-            keys_values.insert(String::from("query-target-filter"), qtf);
+        let mut time_filter = None;
+        if let Some((class_name, time_range)) = self.created_within {
+            match time_range.rest_value() {
+                Some(tr) => {
+                    keys_values.insert(String::from("time-range"), String::from(tr));
+                },
+                None => {
+                    time_filter = Some(time_range.filter_expression(&class_name, Utc::now()));
+                },
+            }
+        }
+        match (self.query_target_filter, time_filter) {
+            (Some(qtf), Some(tf)) => {
+                keys_values.insert(String::from("query-target-filter"), format!("and({},{})", qtf, tf));
+            },
+            (Some(qtf), None) => {
+                keys_values.insert(String::from("query-target-filter"), qtf);
+            },
+            (None, Some(tf)) => {
+                keys_values.insert(String::from("query-target-filter"), tf.to_string());
+            },
+            (None, None) => {},
+        }
+        if let Some(tsc) = self.target_subtree_classes {
+            let classes_str = tsc.iter()
+                .map(|s| s.as_ref())
+                .collect::<Vec<&str>>()
+                .join(",");
+            keys_values.insert(String::from("target-subtree-class"), classes_str);
         }
         keys_values.insert(self.response_subtree.rest_key(), self.response_subtree.rest_value());
         if let Some(rsc) = self.response_subtree_classes {
@@ -381,112 +860,823 @@ impl QuerySettings {
                 .join(",");
             keys_values.insert(String::from("rsp-subtree-class"), classes_str);
         }
+        if let Some(rsf) = self.response_subtree_filter {
+            keys_values.insert(String::from("rsp-subtree-filter"), rsf);
+        }
         if let Some(rsi) = self.response_subtree_include {
             keys_values.insert(rsi.rest_key(), rsi.rest_value());
         }
         keys_values.insert(self.response_property_include.rest_key(), self.response_property_include.rest_value());
+        if let Some(ps) = self.page_size {
+            keys_values.insert(String::from("page-size"), ps.to_string());
+        }
+        if let Some(p) = self.page {
+            keys_values.insert(String::from("page"), p.to_string());
+        }
+        if !self.order_by.is_empty() {
+            let order_str = self.order_by.iter()
+                .map(|(prop, order)| format!("{}|{}", prop, order.rest_value()))
+                .collect::<Vec<String>>()
+                .join(",");
+            keys_values.insert(String::from("order-by"), order_str);
+        }
+        if self.subscription {
+            keys_values.insert(String::from("subscription"), String::from("yes"));
+        }
 
         keys_values
     }
 }
+impl Default for QuerySettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// An action performed when an ApicConnection is dropped, receiving the connection's current
+/// authentication data.
+struct DropAction(Box<dyn FnOnce(ApicAuthenticatorData) + Send + Sync>);
+impl fmt::Debug for DropAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DropAction")
+            .finish()
+    }
+}
+
+
+/// Settings shared by `ApicConnectionBuilder` and `ApicMultiConnectionBuilder`.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionSettings {
+    pub timeout: Duration,
+    pub login_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub tls_options: TlsOptions,
+    pub proxy: Option<ProxyOptions>,
+    pub transport: Option<Arc<dyn HttpTransport>>,
+    pub headers: Vec<(String, String)>,
+    pub prefer_http2: bool,
+    pub retry_policy: RetryPolicy,
+    pub rate_limiter: Option<RateLimiter>,
+}
+impl ConnectionSettings {
+    /// Creates new connection settings with defaults matching those of `ApicConnection::new`.
+    pub fn new() -> ConnectionSettings {
+        ConnectionSettings {
+            timeout: DEFAULT_TIMEOUT,
+            login_timeout: None,
+            connect_timeout: None,
+            tls_options: TlsOptions::new(),
+            proxy: None,
+            transport: None,
+            headers: Vec::new(),
+            prefer_http2: false,
+            retry_policy: RetryPolicy::never(),
+            rate_limiter: None,
+        }
+    }
+
+    /// Returns the timeout applied to login, session refresh and logout requests.
+    pub fn login_timeout(&self) -> Duration {
+        self.login_timeout.unwrap_or(self.timeout)
+    }
+
+    /// Returns the settings for establishing connections which are independent of TLS.
+    pub fn connector_settings(&self) -> ConnectorSettings {
+        ConnectorSettings {
+            proxy: self.proxy.clone(),
+            connect_timeout: self.connect_timeout,
+            prefer_http2: self.prefer_http2,
+        }
+    }
+
+    /// Creates the transport described by these settings.
+    pub fn build_transport(&self) -> Result<Arc<dyn HttpTransport>, ApicCommError> {
+        let transport = match &self.transport {
+            Some(t) => t.clone(),
+            None => self.tls_options.transport_with(&self.connector_settings())
+                .map_err(ApicCommError::Tls)?,
+        };
+        if self.headers.is_empty() {
+            return Ok(transport);
+        }
+
+        let mut headers = Vec::with_capacity(self.headers.len());
+        for (name, value) in &self.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| ApicCommError::ErrorAssemblingRequest(e.into()))?;
+            let header_value = HeaderValue::from_str(value)
+                .map_err(|e| ApicCommError::ErrorAssemblingRequest(e.into()))?;
+            headers.push((header_name, header_value));
+        }
+        Ok(Arc::new(DefaultHeadersTransport {
+            inner: transport,
+            headers,
+        }))
+    }
+}
+
+
+/// Assembles an `ApicConnection` with non-default settings.
+///
+/// Obtained using `ApicConnection::builder`.
+#[derive(Clone, Debug)]
+pub struct ApicConnectionBuilder<A: ApicAuthenticator> {
+    base_uri: Url,
+    authenticator: A,
+    settings: ConnectionSettings,
+    session: Option<ApicSession>,
+    session_cache: Option<PathBuf>,
+}
+impl<A: ApicAuthenticator> ApicConnectionBuilder<A> {
+    /// Sets the timeout applied to each request (including establishing the connection and
+    /// receiving the response) and returns the builder. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout applied to login, session refresh and logout requests and returns the
+    /// builder. Defaults to the request timeout.
+    pub fn login_timeout(mut self, login_timeout: Duration) -> Self {
+        self.settings.login_timeout = Some(login_timeout);
+        self
+    }
+
+    /// Sets the timeout applied to establishing TCP connections and returns the builder. By
+    /// default, only the request timeout applies.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.settings.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets the TLS options and returns the builder.
+    pub fn tls_options(mut self, tls_options: TlsOptions) -> Self {
+        self.settings.tls_options = tls_options;
+        self
+    }
+
+    /// Sets the proxy to connect through and returns the builder.
+    pub fn proxy(mut self, proxy: ProxyOptions) -> Self {
+        self.settings.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the transport used to send requests and returns the builder.
+    ///
+    /// A custom transport replaces the TLS options, the proxy, the connect timeout and the HTTP/2
+    /// preference for REST API requests. WebSocket subscriptions do not use the transport, so they
+    /// still apply the TLS options, the proxy and the connect timeout.
+    pub fn transport(mut self, transport: Arc<dyn HttpTransport>) -> Self {
+        self.settings.transport = Some(transport);
+        self
+    }
+
+    /// Sets the User-Agent header sent with each request and returns the builder.
+    pub fn user_agent(self, user_agent: &str) -> Self {
+        self.header(USER_AGENT.as_str(), user_agent)
+    }
+
+    /// Adds a header sent with each request and returns the builder.
+    ///
+    /// Headers set by the authenticator take precedence. Invalid header names or values are
+    /// reported by `build`.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.settings.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.settings.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Sets whether HTTP/2 is offered to the APIC during the TLS handshake and returns the
+    /// builder. If the APIC does not accept it, HTTP/1.1 is used.
+    pub fn prefer_http2(mut self, prefer_http2: bool) -> Self {
+        self.settings.prefer_http2 = prefer_http2;
+        self
+    }
+
+    /// Sets the retry policy applied to idempotent requests and returns the builder.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.settings.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the rate limiter applied to requests, including the initial login, and returns the
+    /// builder.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.settings.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sets the session to resume and returns the builder.
+    ///
+    /// The session is validated by refreshing it. If the session belongs to a different APIC, has
+    /// already timed out or cannot be refreshed, a new session is established by logging in.
+    pub fn session(mut self, session: ApicSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Sets the file in which the session is cached and returns the builder.
+    ///
+    /// Unless a session to resume has been set using `session`, the session stored in the file is
+    /// resumed if possible. Once connected, the resulting session is stored in the file. Failures
+    /// to read or write the file are logged but otherwise ignored.
+    pub fn session_cache<P: AsRef<Path>>(mut self, cache_path: P) -> Self {
+        self.session_cache = Some(cache_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Creates the connection and logs in or resumes the session.
+    pub async fn build(self) -> Result<ApicConnection<A>, ApicCommError> {
+        let transport = self.settings.build_transport()?;
+
+        let session = match (self.session, &self.session_cache) {
+            (Some(s), _) => Some(s),
+            (None, Some(cache_path)) => match ApicSession::load(cache_path) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    debug!("not using session cache {}: {}", cache_path.display(), e);
+                    None
+                },
+            },
+            (None, None) => None,
+        };
+        let conn = match session {
+            Some(s) => ApicConnection::resume(self.base_uri, transport, self.authenticator, &self.settings, &s).await?,
+            None => ApicConnection::connect(self.base_uri, transport, self.authenticator, &self.settings).await?,
+        };
+
+        if let Some(cache_path) = &self.session_cache {
+            if let Err(e) = conn.save_session(cache_path).await {
+                warn!("failed to update session cache {}: {}", cache_path.display(), e);
+            }
+        }
+        Ok(conn)
+    }
+}
 
 
 /// A connection to an Application Policy Infrastructure Controller (APIC).
+///
+/// The session state is kept behind locks, so a connection can be shared between tasks (e.g. using
+/// an `Arc`) and its session can be refreshed without exclusive access.
 #[derive(Debug)]
 pub struct ApicConnection<A: ApicAuthenticator> {
     base_uri: Url,
-    client: Client<HttpsConnector<HttpConnector>, Body>,
+    client: Arc<dyn HttpTransport>,
     authenticator: A,
-    auth_data: ApicAuthenticatorData,
-    last_login: RwLock<Instant>,
+    auth_data: RwLock<ApicAuthenticatorData>,
+    last_login: RwLock<Option<Instant>>,
     timeout: Duration,
+    login_timeout: Duration,
+    keep_alive_stop: Mutex<Option<oneshot::Sender<()>>>,
+    logout_on_drop: Mutex<Option<DropAction>>,
+    retry_policy: RwLock<RetryPolicy>,
+    rate_limiter: RwLock<Option<RateLimiter>>,
+    tls_options: TlsOptions,
+    connector_settings: ConnectorSettings,
 }
 impl<A: ApicAuthenticator> ApicConnection<A> {
     /// Creates a new APIC connection object.
+    ///
+    /// This is a shorthand for `ApicConnection::builder(base_uri, authenticator).timeout(timeout)
+    /// .build()`.
     pub async fn new(
         base_uri: Url,
         authenticator: A,
         timeout: Duration,
     ) -> Result<Self, ApicCommError> {
-        let https = HttpsConnector::new();
-        let client = Client::builder()
-            .build::<_, Body>(https);
-        let mut me = Self {
+        Self::builder(base_uri, authenticator)
+            .timeout(timeout)
+            .build()
+            .await
+    }
+
+    /// Returns a builder for an APIC connection object with non-default settings.
+    pub fn builder(base_uri: Url, authenticator: A) -> ApicConnectionBuilder<A> {
+        ApicConnectionBuilder {
             base_uri,
-            client,
             authenticator,
-            auth_data: Default::default(),
-            last_login: RwLock::new(Instant::now()),
-            timeout: timeout,
-        };
+            settings: ConnectionSettings::new(),
+            session: None,
+            session_cache: None,
+        }
+    }
+
+    /// Creates a new APIC connection object that sends its requests using the given transport,
+    /// applying the remaining given settings, and logs in.
+    pub(crate) async fn connect(
+        base_uri: Url,
+        transport: Arc<dyn HttpTransport>,
+        authenticator: A,
+        settings: &ConnectionSettings,
+    ) -> Result<Self, ApicCommError> {
+        let me = Self::unauthenticated(base_uri, transport, authenticator, settings);
         me.login().await?;
-        assert_ne!(me.auth_data, Default::default());
+        assert_ne!(*me.auth_data.read().await, Default::default());
         Ok(me)
     }
 
-    /// Returns the instant at which the last authentication was performed.
-    pub async fn last_login(&self) -> Instant {
-        *self.last_login.read()
-            .await
-    }
+    /// Creates a new APIC connection object that sends its requests using the given transport,
+    /// applying the remaining given settings, and resumes the given session.
+    ///
+    /// See `ApicConnectionBuilder::session` for details.
+    async fn resume(
+        base_uri: Url,
+        transport: Arc<dyn HttpTransport>,
+        authenticator: A,
+        settings: &ConnectionSettings,
+        session: &ApicSession,
+    ) -> Result<Self, ApicCommError> {
+        let me = Self::unauthenticated(base_uri, transport, authenticator, settings);
 
-    /// Returns whether the current authentication state would benefit from refreshing.
-    pub async fn should_refresh_login(&self) -> bool {
-        let last_login = self.last_login().await;
-        let time_since_login = match Instant::now().checked_duration_since(last_login) {
-            None => {
-                // last login is in the future (?!); assume we are fine
-                return false;
-            },
-            Some(tsl) => tsl,
-        };
-        let timeout = self.auth_data.refresh_timeout();
-        if timeout <= time_since_login {
-            // already timed out
-            true
-        } else if timeout - time_since_login < REFRESH_BEFORE_TIMEOUT {
-            true
+        let now = SystemTime::now();
+        if session.base_uri() != &me.base_uri {
+            debug!("cached session belongs to {}, not {}; logging in", session.base_uri(), me.base_uri);
+        } else if session.is_expired_at(now) || *session.auth_data() == Default::default() {
+            debug!("cached session has expired; logging in");
         } else {
-            false
+            let since_login = now.duration_since(session.login_time())
+                .unwrap_or_default();
+            *me.auth_data.write().await = session.auth_data().clone();
+            *me.last_login.write().await = Some(
+                Instant::now().checked_sub(since_login)
+                    .unwrap_or_else(Instant::now)
+            );
+            match me.refresh().await {
+                Ok(()) => return Ok(me),
+                Err(e) => debug!("failed to refresh cached session ({}); logging in", e),
+            }
         }
-    }
 
-    /// Authenticates with the APIC, creating a new session.
-    pub async fn login(&mut self) -> Result<(), ApicCommError> {
-        // lock the last login info while the login is being performed
-        let mut last_login = self.last_login.write()
-            .await;
+        me.login().await?;
+        assert_ne!(*me.auth_data.read().await, Default::default());
+        Ok(me)
+    }
 
-        let auth_data = self.authenticator
-            .login(&self.client, &self.base_uri, self.timeout)
-            .await?;
-        self.auth_data = auth_data;
-        *last_login = Instant::now();
+    /// Creates a new ApicConnection that has not yet logged in.
+    fn unauthenticated(
+        base_uri: Url,
+        client: Arc<dyn HttpTransport>,
+        authenticator: A,
+        settings: &ConnectionSettings,
+    ) -> Self {
+        Self {
+            base_uri,
+            client,
+            authenticator,
+            auth_data: RwLock::new(Default::default()),
+            last_login: RwLock::new(None),
+            timeout: settings.timeout,
+            login_timeout: settings.login_timeout(),
+            keep_alive_stop: Mutex::new(None),
+            logout_on_drop: Mutex::new(None),
+            retry_policy: RwLock::new(settings.retry_policy.clone()),
+            rate_limiter: RwLock::new(settings.rate_limiter.clone()),
+            tls_options: settings.tls_options.clone(),
+            connector_settings: settings.connector_settings(),
+        }
+    }
+
+    /// Returns a snapshot of the current session, which can be resumed later using
+    /// `ApicConnectionBuilder::session`.
+    pub async fn session(&self) -> ApicSession {
+        // hold the last login lock to obtain a consistent snapshot
+        let last_login = self.last_login.read()
+            .await;
+        let auth_data = self.auth_data.read()
+            .await
+            .clone();
+        let since_login = last_login
+            .map(|ll| Instant::now().saturating_duration_since(ll))
+            .unwrap_or_default();
+        let login_time = SystemTime::now().checked_sub(since_login)
+            .unwrap_or_else(SystemTime::now);
+        ApicSession::new(self.base_uri.clone(), auth_data, login_time)
+    }
+
+    /// Stores a snapshot of the current session in the given file.
+    pub async fn save_session<P: AsRef<Path>>(&self, path: P) -> Result<(), SessionCacheError> {
+        self.session()
+            .await
+            .save(path)
+    }
+
+    /// Returns the transport used to send requests to the APIC.
+    pub fn transport(&self) -> &Arc<dyn HttpTransport> {
+        &self.client
+    }
+
+    /// Returns the timeout applied to requests to the APIC.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns the retry policy applied to idempotent requests that do not specify their own.
+    pub async fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.read()
+            .await
+            .clone()
+    }
+
+    /// Sets the retry policy applied to idempotent requests (queries and deletions) that do not
+    /// specify their own.
+    ///
+    /// By default, requests are not retried.
+    pub async fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        *self.retry_policy.write().await = retry_policy;
+    }
+
+    /// Returns the rate limiter applied to requests to the APIC, if any.
+    pub async fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.read()
+            .await
+            .clone()
+    }
+
+    /// Sets the rate limiter applied to requests to the APIC, including logins and session
+    /// refreshes.
+    ///
+    /// To share a budget between multiple connections, pass clones of the same RateLimiter to each
+    /// of them. By default, requests are not limited.
+    pub async fn set_rate_limiter(&self, rate_limiter: Option<RateLimiter>) {
+        *self.rate_limiter.write().await = rate_limiter;
+    }
+
+    /// Performs the given request once the rate limiter (if any) admits it, and informs the rate
+    /// limiter about its outcome.
+    async fn rate_limited<T, F>(&self, request: F) -> Result<T, ApicCommError>
+            where F: Future<Output = Result<T, ApicCommError>> {
+        let rate_limiter = self.rate_limiter().await;
+        match rate_limiter {
+            None => request.await,
+            Some(rl) => {
+                let _permit = rl.acquire().await;
+                let result = request.await;
+                rl.report(&result);
+                result
+            },
+        }
+    }
+
+    /// Returns the timeout applied to login, session refresh and logout requests.
+    pub fn login_timeout(&self) -> Duration {
+        self.login_timeout
+    }
+
+    /// Returns the instant at which the last authentication was performed, or `None` if the
+    /// connection has no session (e.g. after logging out).
+    pub async fn last_login(&self) -> Option<Instant> {
+        *self.last_login.read()
+            .await
+    }
+
+    /// Returns whether the current authentication state would benefit from refreshing.
+    pub async fn should_refresh_login(&self) -> bool {
+        match self.last_login().await {
+            Some(last_login) => self.is_refresh_due(last_login).await,
+            None => false,
+        }
+    }
+
+    /// Returns whether a session established at the given instant would benefit from refreshing.
+    async fn is_refresh_due(&self, last_login: Instant) -> bool {
+        let time_since_login = match Instant::now().checked_duration_since(last_login) {
+            None => {
+                // last login is in the future (?!); assume we are fine
+                return false;
+            },
+            Some(tsl) => tsl,
+        };
+        // true if already timed out or about to time out
+        time_since_login >= self.refresh_after().await
+    }
+
+    /// Returns the duration after which the session should be refreshed to prevent it from timing
+    /// out.
+    async fn refresh_due_in(&self) -> Duration {
+        let last_login = match self.last_login().await {
+            Some(ll) => ll,
+            None => return Duration::from_secs(0),
+        };
+        let time_since_login = Instant::now().saturating_duration_since(last_login);
+        self.refresh_after().await
+            .checked_sub(time_since_login)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Returns the duration after login at which the session should be refreshed.
+    async fn refresh_after(&self) -> Duration {
+        let timeout = self.auth_data.read()
+            .await
+            .refresh_timeout();
+        if timeout > REFRESH_BEFORE_TIMEOUT {
+            timeout - REFRESH_BEFORE_TIMEOUT
+        } else {
+            // very short session; refresh halfway through
+            timeout / 2
+        }
+    }
+
+    /// Authenticates with the APIC, creating a new session.
+    pub async fn login(&self) -> Result<(), ApicCommError> {
+        // lock the last login info while the login is being performed
+        let mut last_login = self.last_login.write()
+            .await;
+        self.login_locked(&mut last_login).await
+    }
+
+    /// Authenticates with the APIC, given the locked instant of the last login.
+    async fn login_locked(&self, last_login: &mut Option<Instant>) -> Result<(), ApicCommError> {
+        let auth_data = self.rate_limited(
+            self.authenticator.login(&*self.client, &self.base_uri, self.login_timeout)
+        ).await?;
+        *self.auth_data.write().await = auth_data;
+        *last_login = Some(Instant::now());
 
         Ok(())
     }
 
+    /// Authenticates with the APIC if the connection has no session, e.g. after logging out.
+    pub(crate) async fn ensure_session(&self) -> Result<(), ApicCommError> {
+        if self.last_login.read().await.is_some() {
+            return Ok(());
+        }
+        let mut last_login = self.last_login.write()
+            .await;
+        if last_login.is_some() {
+            // someone else has already logged in
+            return Ok(());
+        }
+        self.login_locked(&mut last_login).await
+    }
+
     /// Refreshes the current authentication session with the APIC.
-    pub async fn refresh(&mut self) -> Result<(), ApicCommError> {
-        assert_ne!(self.auth_data, Default::default());
+    pub async fn refresh(&self) -> Result<(), ApicCommError> {
         let mut last_login = self.last_login.write()
             .await;
+        self.refresh_locked(&mut last_login).await
+    }
 
-        let auth_data = self.authenticator
-            .refresh(&self.client, &self.base_uri, self.timeout, &self.auth_data)
-            .await?;
-        self.auth_data = auth_data;
-        *last_login = Instant::now();
+    /// Refreshes the current authentication session with the APIC if it would benefit from
+    /// refreshing.
+    ///
+    /// The need is checked again once the session is locked, so if multiple tasks call this at the
+    /// same time, the session is only refreshed once. If the APIC rejects the refresh because the
+    /// session has already timed out, or if the connection has no session, a new session is
+    /// created instead.
+    pub async fn refresh_if_needed(&self) -> Result<(), ApicCommError> {
+        let mut last_login = self.last_login.write()
+            .await;
+        match *last_login {
+            None => return self.login_locked(&mut last_login).await,
+            Some(ll) if !self.is_refresh_due(ll).await => {
+                // someone else has already refreshed the session
+                return Ok(());
+            },
+            Some(_) => {},
+        }
+        match self.refresh_locked(&mut last_login).await {
+            Err(e) if e.is_session_rejection() => {
+                warn!("APIC rejected the session refresh ({}); logging in again", e);
+                self.login_locked(&mut last_login).await
+            },
+            other => other,
+        }
+    }
+
+    /// Refreshes the current authentication session with the APIC, given the locked instant of the
+    /// last login.
+    async fn refresh_locked(&self, last_login: &mut Option<Instant>) -> Result<(), ApicCommError> {
+        if last_login.is_none() {
+            return Err(ApicCommError::NoSession);
+        }
+        let current_data = self.auth_data.read()
+            .await
+            .clone();
+        let auth_data = self.rate_limited(
+            self.authenticator.refresh(&*self.client, &self.base_uri, self.login_timeout, &current_data)
+        ).await?;
+        *self.auth_data.write().await = auth_data;
+        *last_login = Some(Instant::now());
 
         Ok(())
     }
 
-    /// Returns the instances of the given class.
-    pub async fn get_instances(
+    /// Starts a background task that keeps the session alive by refreshing it shortly before it
+    /// times out.
+    ///
+    /// If refreshing the session fails, the task attempts to log in again. The task stops when the
+    /// connection is dropped or `stop_keep_alive` is called. Starting a new keep-alive task stops
+    /// the previous one.
+    pub async fn start_keep_alive(self: &Arc<Self>) -> JoinHandle<()>
+            where A: Send + Sync + 'static {
+        let (stop_sender, mut stop_receiver) = oneshot::channel();
+        *self.keep_alive_stop.lock().await = Some(stop_sender);
+
+        let weak_conn: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut delay = match weak_conn.upgrade() {
+                Some(conn) => conn.refresh_due_in().await,
+                None => return,
+            };
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = &mut stop_receiver => {
+                        // stopped or connection dropped
+                        break;
+                    },
+                }
+
+                let conn = match weak_conn.upgrade() {
+                    Some(c) => c,
+                    None => break,
+                };
+                debug!("keep-alive: refreshing session with {}", conn.base_uri);
+                // a request might have renewed the session in the meantime
+                let renewed = match conn.refresh_if_needed().await {
+                    Ok(()) => true,
+                    Err(refresh_err) => {
+                        warn!("keep-alive: failed to refresh session ({}); logging in again", refresh_err);
+                        match conn.login().await {
+                            Ok(()) => true,
+                            Err(login_err) => {
+                                warn!("keep-alive: failed to log in again: {}", login_err);
+                                false
+                            },
+                        }
+                    },
+                };
+                delay = if renewed {
+                    conn.refresh_due_in().await
+                } else {
+                    KEEP_ALIVE_RETRY_DELAY
+                };
+            }
+            debug!("keep-alive task stopped");
+        })
+    }
+
+    /// Stops the background task started by `start_keep_alive`, if any.
+    pub async fn stop_keep_alive(&self) {
+        if let Some(stop_sender) = self.keep_alive_stop.lock().await.take() {
+            // the task might have already stopped; that's fine
+            let _ = stop_sender.send(());
+        }
+    }
+
+    /// Terminates the current session with the APIC.
+    ///
+    /// This also stops the keep-alive task and disables logging out on drop. The connection is left
+    /// without a session, even if the APIC does not confirm the logout; performing further requests
+    /// using this connection logs in again.
+    pub async fn logout(&self) -> Result<(), ApicCommError> {
+        self.stop_keep_alive().await;
+        *self.logout_on_drop.lock().await = None;
+
+        let mut last_login = self.last_login.write()
+            .await;
+        if last_login.is_none() {
+            // already logged out
+            return Ok(());
+        }
+        let current_data = std::mem::take(&mut *self.auth_data.write().await);
+        *last_login = None;
+        self.rate_limited(
+            self.authenticator.logout(&*self.client, &self.base_uri, self.login_timeout, &current_data)
+        ).await
+    }
+
+    /// Sets whether the session should be terminated when this connection is dropped.
+    ///
+    /// Since dropping is synchronous, the logout is performed by a task spawned on the current
+    /// Tokio runtime, which is not waited for. If the runtime is shut down shortly afterwards
+    /// (e.g. at the end of `main`), the logout might not complete; call `logout` explicitly in that
+    /// case.
+    pub async fn set_logout_on_drop(&self, logout_on_drop: bool)
+            where A: Clone + Send + Sync + 'static {
+        let action = if logout_on_drop {
+            let client = self.client.clone();
+            let authenticator = self.authenticator.clone();
+            let base_uri = self.base_uri.clone();
+            let timeout = self.login_timeout;
+            Some(DropAction(Box::new(move |auth_data: ApicAuthenticatorData| {
+                let handle = match tokio::runtime::Handle::try_current() {
+                    Ok(h) => h,
+                    Err(_) => {
+                        warn!("cannot log out from {} on drop outside of a Tokio runtime", base_uri);
+                        return;
+                    },
+                };
+                handle.spawn(async move {
+                    let logout_res = authenticator
+                        .logout(&*client, &base_uri, timeout, &auth_data)
+                        .await;
+                    if let Err(e) = logout_res {
+                        warn!("failed to log out from {} on drop: {}", base_uri, e);
+                    }
+                });
+            })))
+        } else {
+            None
+        };
+        *self.logout_on_drop.lock().await = action;
+    }
+
+    /// Authenticates with the APIC again after it has rejected the session described by
+    /// `rejected_data`.
+    ///
+    /// If another request has already replaced the rejected session in the meantime, no new login
+    /// is performed.
+    async fn login_after_rejection(&self, rejected_data: &ApicAuthenticatorData) -> Result<(), ApicCommError> {
+        let mut last_login = self.last_login.write()
+            .await;
+        if *self.auth_data.read().await != *rejected_data {
+            // someone else has already logged in again
+            return Ok(());
+        }
+        self.login_locked(&mut last_login).await
+    }
+
+    /// Performs a JSON request against the APIC within the current session.
+    ///
+    /// If the APIC rejects the session (e.g. because it has expired or the APIC has been
+    /// rebooted), logs in again and retries the request once.
+    async fn perform_session_request(
+        &self,
+        uri: Url,
+        method: &str,
+        body: Option<JsonValue>,
+    ) -> Result<JsonValue, ApicCommError> {
+        self.ensure_session().await?;
+        let mut retried = false;
+        loop {
+            let auth_data = self.auth_data.read()
+                .await
+                .clone();
+            let body_string = body.as_ref()
+                .map(|b| b.dump());
+            let mut headers = self.authenticator
+                .request_headers(&auth_data, method, &uri, body_string.as_deref());
+            headers.insert("Accept".into(), "application/json".into());
+
+            let result = self.rate_limited(perform_json_request(
+                &*self.client,
+                uri.clone(),
+                method,
+                &headers,
+                body.clone(),
+                self.timeout,
+            )).await;
+            match result {
+                Err(e) if !retried && e.is_session_rejection() => {
+                    warn!("APIC rejected the session ({}); logging in again", e);
+                    self.login_after_rejection(&auth_data).await?;
+                    retried = true;
+                },
+                other => return other,
+            }
+        }
+    }
+
+    /// Performs an idempotent JSON request against the APIC within the current session, retrying it
+    /// according to the given retry policy or, if none is given, the connection's retry policy.
+    async fn perform_idempotent_request(
+        &self,
+        uri: Url,
+        method: &str,
+        retry_policy: Option<RetryPolicy>,
+    ) -> Result<JsonValue, ApicCommError> {
+        let retry_policy = match retry_policy {
+            Some(rp) => rp,
+            None => self.retry_policy().await,
+        };
+        let mut attempt = 1;
+        loop {
+            let result = self.perform_session_request(uri.clone(), method, None).await;
+            match result {
+                Err(e) if retry_policy.should_retry(&e, attempt) => {
+                    let delay = retry_policy.backoff(attempt);
+                    warn!(
+                        "{} {} failed on attempt {} ({}); retrying in {:?}",
+                        method, uri, attempt, e, delay,
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                other => return other,
+            }
+        }
+    }
+
+    /// Queries the instances of the given class and returns the raw JSON response.
+    async fn query_instances(
         &self,
         class_name: &str,
         query_settings: QuerySettings,
-    ) -> Result<Vec<AciObject>, ApicCommError> {
+    ) -> Result<JsonValue, ApicCommError> {
+        let retry_policy = query_settings.retry_policy.clone();
         let query_settings_map = query_settings.to_aci_keys_values();
 
         let mut query_uri = self.base_uri.clone();
@@ -504,29 +1694,60 @@ impl<A: ApicAuthenticator> ApicConnection<A> {
                 .append_pair(k, v);
         }
 
-        let mut headers = self.auth_data.as_headers();
-        headers.insert("Accept".into(), "application/json".into());
-
-        let json_value = perform_json_request(
-            &self.client,
+        self.perform_idempotent_request(
             query_uri,
             "GET",
-            &headers,
-            None,
-            self.timeout,
-        ).await?;
+            retry_policy,
+        ).await
+    }
+
+    /// Returns the instances of the given class.
+    pub async fn get_instances(
+        &self,
+        class_name: &str,
+        query_settings: QuerySettings,
+    ) -> Result<Vec<AciObject>, ApicCommError> {
+        let json_value = self.query_instances(class_name, query_settings).await?;
         let aci_objects = json_to_aci_objects(json_value.clone())
             .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, json_value))?;
         Ok(aci_objects)
     }
 
-    /// Returns the managed object with the given Distinguished Name (or some of its children or
-    /// descendants, depending on the query settings).
-    pub async fn get_objects(
+    /// Returns the instances of the given class along with the metadata of the response.
+    pub async fn get_instances_with_metadata(
+        &self,
+        class_name: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        let json_value = self.query_instances(class_name, query_settings).await?;
+        json_to_query_result(json_value.clone())
+            .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, json_value))
+    }
+
+    /// Returns a stream of the instances of the given class, fetching them lazily from the APIC in
+    /// pages of the given size.
+    ///
+    /// Any paging options set in `query_settings` are overridden. To ensure that pages do not
+    /// overlap, specify a sort order using `QuerySettings::order_by`.
+    pub fn stream_instances<'a>(
+        &'a self,
+        class_name: &'a str,
+        query_settings: QuerySettings,
+        page_size: NonZeroU64,
+    ) -> impl Stream<Item = Result<AciObject, ApicCommError>> + 'a {
+        paged_stream(page_size, move |page| {
+            self.get_instances_with_metadata(class_name, query_settings.clone().page(page_size, page))
+        })
+    }
+
+    /// Queries the managed object with the given Distinguished Name and returns the raw JSON
+    /// response.
+    async fn query_objects(
         &self,
         dn: &str,
         query_settings: QuerySettings,
-    ) -> Result<Vec<AciObject>, ApicCommError> {
+    ) -> Result<JsonValue, ApicCommError> {
+        let retry_policy = query_settings.retry_policy.clone();
         let query_settings_map = query_settings.to_aci_keys_values();
 
         let mut query_uri = self.base_uri.clone();
@@ -544,23 +1765,131 @@ impl<A: ApicAuthenticator> ApicConnection<A> {
                 .append_pair(k, v);
         }
 
-        let mut headers = self.auth_data.as_headers();
-        headers.insert("Accept".into(), "application/json".into());
-
-        let json_value = perform_json_request(
-            &self.client,
+        self.perform_idempotent_request(
             query_uri,
             "GET",
-            &headers,
-            None,
-            self.timeout,
-        ).await?;
+            retry_policy,
+        ).await
+    }
+
+    /// Returns the managed object with the given Distinguished Name (or some of its children or
+    /// descendants, depending on the query settings).
+    pub async fn get_objects(
+        &self,
+        dn: &str,
+        query_settings: QuerySettings,
+    ) -> Result<Vec<AciObject>, ApicCommError> {
+        let json_value = self.query_objects(dn, query_settings).await?;
         let aci_objects = json_to_aci_objects(json_value.clone())
             .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, json_value))?;
         Ok(aci_objects)
     }
 
+    /// Returns the managed object with the given Distinguished Name (or some of its children or
+    /// descendants, depending on the query settings) along with the metadata of the response.
+    pub async fn get_objects_with_metadata(
+        &self,
+        dn: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        let json_value = self.query_objects(dn, query_settings).await?;
+        json_to_query_result(json_value.clone())
+            .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, json_value))
+    }
+
+    /// Returns the URI of the WebSocket through which the APIC pushes notifications about changes
+    /// to subscribed objects during the current session.
+    ///
+    /// If the connection has no session (e.g. after logging out), the URI is not usable.
+    pub async fn websocket_uri(&self) -> Url {
+        let mut ws_uri = self.base_uri.clone();
+        let ws_scheme = if ws_uri.scheme() == "http" { "ws" } else { "wss" };
+        ws_uri.set_scheme(ws_scheme)
+            .expect("failed to set WebSocket scheme");
+        {
+            let mut segs = ws_uri.path_segments_mut()
+                .expect("base URI does not have editable path segments");
+            segs.pop_if_empty();
+            segs.push(&format!("socket{}", self.auth_data.read().await.apic_cookie()));
+        }
+        ws_uri
+    }
+
+    /// Establishes a connection to the host of the given WebSocket URI, applying the TLS options,
+    /// the proxy and the connect timeout of this connection.
+    pub(crate) async fn connect_websocket_stream(
+        &self,
+        websocket_uri: &Url,
+    ) -> Result<TlsStream, Box<dyn Error + Send + Sync>> {
+        let scheme = if websocket_uri.scheme() == "ws" { "http" } else { "https" };
+        let host = websocket_uri.host_str()
+            .ok_or("WebSocket URI is missing a host")?;
+        let port = websocket_uri.port_or_known_default()
+            .ok_or("WebSocket URI is missing a port")?;
+        let uri: hyper::Uri = format!("{}://{}:{}/", scheme, host, port).parse()?;
+
+        // WebSockets require HTTP/1.1
+        let settings = ConnectorSettings {
+            prefer_http2: false,
+            ..self.connector_settings.clone()
+        };
+        self.tls_options.connect(&settings, uri).await
+    }
+
+    /// Returns the instances of the given class and subscribes to changes to them.
+    ///
+    /// The ID of the subscription is available through `QueryResult::subscription_id`. Change
+    /// notifications are delivered through the WebSocket at `websocket_uri`, which must already be
+    /// open; consider using `ApicSubscription` instead of calling this function directly.
+    pub async fn subscribe_instances(
+        &self,
+        class_name: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        self.get_instances_with_metadata(class_name, query_settings.subscription(true)).await
+    }
+
+    /// Returns the managed object with the given Distinguished Name (or some of its children or
+    /// descendants, depending on the query settings) and subscribes to changes to them.
+    ///
+    /// The ID of the subscription is available through `QueryResult::subscription_id`. Change
+    /// notifications are delivered through the WebSocket at `websocket_uri`, which must already be
+    /// open; consider using `ApicSubscription` instead of calling this function directly.
+    pub async fn subscribe_objects(
+        &self,
+        dn: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        self.get_objects_with_metadata(dn, query_settings.subscription(true)).await
+    }
+
+    /// Refreshes the subscription with the given ID, preventing it from expiring.
+    pub async fn refresh_subscription(
+        &self,
+        subscription_id: &str,
+    ) -> Result<(), ApicCommError> {
+        let mut query_uri = self.base_uri.clone();
+
+        {
+            let mut segs = query_uri.path_segments_mut()
+                .expect("base URI does not have editable path segments");
+            segs.push("api");
+            segs.push("subscriptionRefresh.json");
+        }
+        query_uri.query_pairs_mut()
+            .append_pair("id", subscription_id);
+
+        self.perform_idempotent_request(
+            query_uri,
+            "GET",
+            None,
+        ).await?;
+        Ok(())
+    }
+
     /// Posts (creates or modifies) the supplied managed object in the fabric.
+    ///
+    /// Since posting is not idempotent, the request is never retried.
     pub async fn post_object(
         &self,
         obj: &AciObject,
@@ -580,16 +1909,10 @@ impl<A: ApicAuthenticator> ApicConnection<A> {
             segs.push(&format!("{}.json", obj_dn));
         }
 
-        let mut headers = self.auth_data.as_headers();
-        headers.insert("Accept".into(), "application/json".into());
-
-        let json_value = perform_json_request(
-            &self.client,
+        let json_value = self.perform_session_request(
             query_uri,
             "POST",
-            &headers,
             Some(obj.to_json()),
-            self.timeout,
         ).await?;
         let aci_objects = json_to_aci_objects(json_value.clone())
             .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, json_value))?;
@@ -597,9 +1920,22 @@ impl<A: ApicAuthenticator> ApicConnection<A> {
     }
 
     /// Deletes the object with the given Distinguished Name from the fabric.
+    ///
+    /// Since deletion is idempotent, the request is retried according to the connection's retry
+    /// policy.
     pub async fn delete_object(
         &self,
         dn: &str,
+    ) -> Result<(), ApicCommError> {
+        self.delete_object_with_retry_policy(dn, None).await
+    }
+
+    /// Deletes the object with the given Distinguished Name from the fabric, retrying the request
+    /// according to the given retry policy or, if none is given, the connection's retry policy.
+    pub async fn delete_object_with_retry_policy(
+        &self,
+        dn: &str,
+        retry_policy: Option<RetryPolicy>,
     ) -> Result<(), ApicCommError> {
         let mut query_uri = self.base_uri.clone();
 
@@ -611,21 +1947,136 @@ impl<A: ApicAuthenticator> ApicConnection<A> {
             segs.push(&format!("{}.json", dn));
         }
 
-        let mut headers = self.auth_data.as_headers();
-        headers.insert("Accept".into(), "application/json".into());
-
-        perform_json_request(
-            &self.client,
+        self.perform_idempotent_request(
             query_uri,
             "DELETE",
-            &headers,
-            None,
-            self.timeout,
+            retry_policy,
         ).await?;
         Ok(())
     }
 }
 
+impl<A: ApicAuthenticator> Drop for ApicConnection<A> {
+    fn drop(&mut self) {
+        if let Some(action) = self.logout_on_drop.get_mut().take() {
+            let auth_data = self.auth_data.get_mut().clone();
+            (action.0)(auth_data);
+        }
+    }
+}
+
+/// An error reported by the Application Policy Infrastructure Controller (APIC).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ApicError {
+    code: u32,
+    text: String,
+    status: StatusCode,
+    dn: Option<String>,
+}
+impl ApicError {
+    /// The error code returned if the requested object does not exist.
+    pub const OBJECT_NOT_FOUND: u32 = 103;
+
+    /// The error code returned if an argument of the request is invalid.
+    pub const INVALID_ARGUMENT: u32 = 400;
+
+    /// Creates a new ApicError with the given code, text, HTTP status and affected Distinguished
+    /// Name.
+    pub fn new(
+        code: u32,
+        text: String,
+        status: StatusCode,
+        dn: Option<String>,
+    ) -> ApicError {
+        ApicError {
+            code,
+            text,
+            status,
+            dn,
+        }
+    }
+
+    /// Attempts to extract the error object from the body of an APIC error response.
+    ///
+    /// The body of an APIC error response has the following form:
+    ///
+    /// ```json
+    /// {
+    ///   "totalCount": "1",
+    ///   "imdata": [
+    ///     {
+    ///       "error": {
+    ///         "attributes": {
+    ///           "code": "103",
+    ///           "text": "..."
+    ///         }
+    ///       }
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    pub fn from_response(body: &JsonValue, status: StatusCode) -> Option<ApicError> {
+        let attribs = &body["imdata"][0]["error"]["attributes"];
+        if !attribs.is_object() {
+            return None;
+        }
+        let code = attribs["code"].as_str()
+            .and_then(|c| c.parse().ok())
+            .or_else(|| attribs["code"].as_u32())?;
+        let text = attribs["text"].as_str()
+            .map(String::from)
+            .unwrap_or_default();
+        let dn = attribs["dn"].as_str()
+            .filter(|dn| !dn.is_empty())
+            .map(String::from);
+        Some(ApicError::new(code, text, status, dn))
+    }
+
+    /// Returns the APIC error code.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// Returns the error message provided by the APIC.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the HTTP status of the error response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the Distinguished Name of the object affected by the error, if the APIC has
+    /// provided it.
+    pub fn dn(&self) -> Option<&str> {
+        self.dn.as_deref()
+    }
+
+    /// Returns whether this error signals that the APIC has rejected the session token because it
+    /// is missing, invalid or has timed out.
+    pub fn is_session_rejection(&self) -> bool {
+        match self.status {
+            StatusCode::UNAUTHORIZED => true,
+            StatusCode::FORBIDDEN
+                => self.text.contains("Token was invalid") || self.text.contains("Token timeout"),
+            _ => false,
+        }
+    }
+}
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "APIC error {} ({}): {}", self.code, self.status, self.text)?;
+        if let Some(dn) = &self.dn {
+            write!(f, " (affected object: {})", dn)?;
+        }
+        Ok(())
+    }
+}
+impl Error for ApicError {
+}
+
+
 /// An error that occurred during communication with the Application Policy Infrastructure
 /// Controller (APIC).
 #[derive(Debug)]
@@ -645,8 +2096,15 @@ pub enum ApicCommError {
     /// An error occurred when obtaining the HTTP response.
     ErrorObtainingResponse(hyper::Error),
 
-    /// An error response has been returned by the APIC.
-    ErrorResponse(JsonValue, hyper::http::response::Parts),
+    /// An error response that does not contain an APIC error object has been returned by the APIC.
+    ///
+    /// If the body of the response is not valid JSON, it is passed as a JSON string.
+    ErrorResponse(JsonValue, Box<hyper::http::response::Parts>),
+
+    /// An error response containing an APIC error object has been returned by the APIC.
+    ///
+    /// The parts of the response (e.g. its headers) are kept alongside the error object.
+    ApicError(ApicError, Box<hyper::http::response::Parts>),
 
     /// The APIC response is not valid UTF-8.
     InvalidUtf8(Utf8Error, hyper::body::Bytes),
@@ -665,6 +2123,18 @@ pub enum ApicCommError {
 
     /// The object passed to the function is missing its "dn" (Distinguished Name) attribute.
     MissingDistinguishedName,
+
+    /// An error occurred while communicating over the notification WebSocket.
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    /// The APIC has not returned a subscription ID for a subscription query.
+    MissingSubscriptionId,
+
+    /// The TLS options could not be applied.
+    Tls(TlsError),
+
+    /// No session has been established with the APIC.
+    NoSession,
 }
 impl fmt::Display for ApicCommError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -681,6 +2151,8 @@ impl fmt::Display for ApicCommError {
                 => write!(f, "error obtaining response: {}", e),
             ApicCommError::ErrorResponse(j, p)
                 => write!(f, "server returned negative response {}: {}", p.status, j),
+            ApicCommError::ApicError(e, _)
+                => write!(f, "server returned error: {}", e),
             ApicCommError::InvalidUtf8(e, _)
                 => write!(f, "server returned response that was not valid UTF-8: {}", e),
             ApicCommError::InvalidJson(e, _)
@@ -693,8 +2165,910 @@ impl fmt::Display for ApicCommError {
                 => write!(f, "no APIC specified"),
             ApicCommError::MissingDistinguishedName
                 => write!(f, "object is missing its Distinguished Name attribute ({:?})", crate::DN_KEY),
+            ApicCommError::WebSocket(e)
+                => write!(f, "WebSocket error: {}", e),
+            ApicCommError::MissingSubscriptionId
+                => write!(f, "missing subscription ID in response"),
+            ApicCommError::Tls(e)
+                => write!(f, "TLS error: {}", e),
+            ApicCommError::NoSession
+                => write!(f, "no session established"),
+        }
+    }
+}
+impl ApicCommError {
+    /// Returns the HTTP status of the error response returned by the APIC, if this error
+    /// represents one.
+    pub fn response_status(&self) -> Option<StatusCode> {
+        match &self {
+            ApicCommError::ErrorResponse(_, p) => Some(p.status),
+            ApicCommError::ApicError(e, _) => Some(e.status()),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this error signals that the APIC has rejected the session token, e.g. because
+    /// the session has expired or has been terminated.
+    ///
+    /// Other errors with the HTTP status 403 (e.g. missing privileges) are not considered session
+    /// rejections.
+    pub fn is_session_rejection(&self) -> bool {
+        match self {
+            ApicCommError::ApicError(e, _) => e.is_session_rejection(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether this error signals a failure to authenticate with the APIC: the credentials
+    /// have been rejected, no session token has been issued, no session has been established or the
+    /// session has been rejected.
+    pub fn is_authentication_failure(&self) -> bool {
+        match self {
+            ApicCommError::InvalidCredentials
+                | ApicCommError::MissingSessionToken(_)
+                | ApicCommError::NoSession => true,
+            other => other.is_session_rejection(),
+        }
+    }
+
+    /// Returns whether this error signals that the connection to the APIC could not be established
+    /// or broke down before a complete response had been received.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            ApicCommError::ErrorObtainingResponse(e)
+                => e.is_connect() || e.is_incomplete_message() || e.is_closed() || e.is_canceled(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the APIC has rejected the request as invalid (HTTP status 4xx).
+    pub fn is_client_error(&self) -> bool {
+        self.response_status()
+            .map(|s| s.is_client_error())
+            .unwrap_or(false)
+    }
+
+    /// Returns whether the APIC has failed to process the request (HTTP status 5xx).
+    pub fn is_server_error(&self) -> bool {
+        self.response_status()
+            .map(|s| s.is_server_error())
+            .unwrap_or(false)
+    }
+
+    /// Returns whether this error is likely to be temporary, i.e. whether performing the same
+    /// request again (possibly against a different APIC) might succeed.
+    ///
+    /// This is the case for timeouts, connection errors, throttling (HTTP status 429) and the HTTP
+    /// statuses 502 (Bad Gateway), 503 (Service Unavailable) and 504 (Gateway Timeout).
+    pub fn is_transient(&self) -> bool {
+        if matches!(self, ApicCommError::Timeout) || self.is_connection_error() {
+            return true;
         }
+        matches!(
+            self.response_status(),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+                | Some(StatusCode::BAD_GATEWAY)
+                | Some(StatusCode::SERVICE_UNAVAILABLE)
+                | Some(StatusCode::GATEWAY_TIMEOUT)
+        )
+    }
+
+    /// Returns whether this error is neither transient nor an authentication failure, i.e. whether
+    /// the request cannot succeed without changing it or the configuration.
+    pub fn is_fatal(&self) -> bool {
+        !self.is_transient() && !self.is_authentication_failure()
     }
 }
 impl Error for ApicCommError {
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures_util::stream::StreamExt;
+
+    use crate::auth::ApicUsernamePasswordAuth;
+    use crate::multi_conn::ApicMultiConnection;
+    use crate::test_server::{self, EMPTY_RESPONSE, LOGIN_RESPONSE};
+
+    fn make_objects(start: u64, count: u64) -> Vec<AciObject> {
+        (start..start+count)
+            .map(|i| {
+                let mut attribs = HashMap::new();
+                attribs.insert(String::from("dn"), format!("uni/tn-{}", i));
+                AciObject::new(String::from("fvTenant"), attribs, Vec::new()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paging_keys_values() {
+        let page_size = NonZeroU64::new(100).unwrap();
+        let kv = QuerySettings::new()
+            .page(page_size, 3)
+            .to_aci_keys_values();
+        assert_eq!(kv.get("page-size").map(|s| s.as_str()), Some("100"));
+        assert_eq!(kv.get("page").map(|s| s.as_str()), Some("3"));
+
+        let kv = QuerySettings::new()
+            .page(page_size, 3)
+            .page_all()
+            .to_aci_keys_values();
+        assert!(!kv.contains_key("page-size"));
+        assert!(!kv.contains_key("page"));
+    }
+
+    #[test]
+    fn order_by_keys_values() {
+        let kv = QuerySettings::new()
+            .order_by("fvTenant.name", SortOrder::Ascending)
+            .order_by("fvTenant.dn", SortOrder::Descending)
+            .to_aci_keys_values();
+        assert_eq!(
+            kv.get("order-by").map(|s| s.as_str()),
+            Some("fvTenant.name|asc,fvTenant.dn|desc"),
+        );
+
+        let kv = QuerySettings::new()
+            .order_by("fvTenant.name", SortOrder::Ascending)
+            .order_by_none()
+            .to_aci_keys_values();
+        assert!(!kv.contains_key("order-by"));
+    }
+
+    #[test]
+    fn subtree_keys_values() {
+        let kv = QuerySettings::new()
+            .query_target(QueryTarget::ConsiderSubtree)
+            .target_subtree_classes(&["fvAEPg", "fvTenant"])
+            .response_subtree(ResponseSubtree::ReturnChildren)
+            .response_subtree_classes(&["faultInst"])
+            .response_subtree_filter_expression(&FilterExpression::equal("faultInst.severity", "critical"))
+            .response_subtree_include(ResponseSubtreeInclude::FAULTS | ResponseSubtreeInclude::REQUIRED)
+            .to_aci_keys_values();
+        assert_eq!(kv.get("query-target").map(|s| s.as_str()), Some("subtree"));
+        let mut target_classes: Vec<&str> = kv["target-subtree-class"].split(',').collect();
+        target_classes.sort_unstable();
+        assert_eq!(target_classes, vec!["fvAEPg", "fvTenant"]);
+        assert_eq!(kv.get("rsp-subtree").map(|s| s.as_str()), Some("children"));
+        assert_eq!(kv.get("rsp-subtree-class").map(|s| s.as_str()), Some("faultInst"));
+        assert_eq!(
+            kv.get("rsp-subtree-filter").map(|s| s.as_str()),
+            Some("eq(faultInst.severity,\"critical\")"),
+        );
+        assert_eq!(kv.get("rsp-subtree-include").map(|s| s.as_str()), Some("faults,required"));
+
+        let kv = QuerySettings::new()
+            .target_subtree_classes(&["fvAEPg"])
+            .target_subtree_classes_all()
+            .response_subtree_filter("eq(faultInst.severity,\"critical\")")
+            .response_subtree_filter_any()
+            .to_aci_keys_values();
+        assert!(!kv.contains_key("target-subtree-class"));
+        assert!(!kv.contains_key("rsp-subtree-filter"));
+    }
+
+    #[test]
+    fn time_range_filters() {
+        use chrono::TimeZone;
+
+        let start = Utc.ymd(2021, 2, 3).and_hms_milli(4, 5, 6, 789);
+        let end = Utc.ymd(2021, 2, 4).and_hms(0, 0, 0);
+
+        let kv = QuerySettings::new()
+            .created_within("faultRecord", TimeRange::Between(start, end))
+            .to_aci_keys_values();
+        assert_eq!(
+            kv.get("query-target-filter").map(|s| s.as_str()),
+            Some("bw(faultRecord.created,\"2021-02-03T04:05:06.789+00:00\",\"2021-02-04T00:00:00.000+00:00\")"),
+        );
+
+        let kv = QuerySettings::new()
+            .query_target_filter("eq(faultRecord.severity,\"critical\")")
+            .created_within("faultRecord", TimeRange::Until(end))
+            .to_aci_keys_values();
+        assert_eq!(
+            kv.get("query-target-filter").map(|s| s.as_str()),
+            Some("and(eq(faultRecord.severity,\"critical\"),le(faultRecord.created,\"2021-02-04T00:00:00.000+00:00\"))"),
+        );
+
+        let kv = QuerySettings::new()
+            .created_within("faultRecord", TimeRange::Since(start))
+            .created_any()
+            .to_aci_keys_values();
+        assert!(!kv.contains_key("query-target-filter"));
+    }
+
+    #[test]
+    fn relative_time_range_filter() {
+        use chrono::TimeZone;
+
+        let now = Utc.ymd(2021, 2, 4).and_hms(12, 0, 0);
+        let expr = TimeRange::Last(Duration::from_secs(24*60*60))
+            .filter_expression("eventRecord", now);
+        assert_eq!(
+            expr,
+            FilterExpression::greater_or_equal("eventRecord.created", "2021-02-03T12:00:00.000+00:00"),
+        );
+
+        let expr = TimeRange::Last(Duration::from_secs(u64::MAX))
+            .filter_expression("eventRecord", now);
+        assert_eq!(
+            expr,
+            FilterExpression::greater_or_equal("eventRecord.created", "1970-01-01T00:00:00.000+00:00"),
+        );
+    }
+
+    #[test]
+    fn relative_time_range_parameter() {
+        let kv = QuerySettings::new()
+            .query_target_filter("eq(faultRecord.severity,\"critical\")")
+            .created_within("faultRecord", TimeRange::Last(Duration::from_secs(7*24*60*60)))
+            .to_aci_keys_values();
+        assert_eq!(kv.get("time-range").map(|s| s.as_str()), Some("1week"));
+        assert_eq!(
+            kv.get("query-target-filter").map(|s| s.as_str()),
+            Some("eq(faultRecord.severity,\"critical\")"),
+        );
+
+        let kv = QuerySettings::new()
+            .created_within("faultRecord", TimeRange::Last(Duration::from_secs(60*60)))
+            .to_aci_keys_values();
+        assert!(!kv.contains_key("time-range"));
+        assert!(kv.contains_key("query-target-filter"));
+    }
+
+    #[test]
+    fn apic_error_from_response() {
+        let body = json::parse(r#"{
+            "totalCount": "1",
+            "imdata": [
+                {"error": {"attributes": {"code": "103", "text": "Unable to find the object", "dn": "uni/tn-x"}}}
+            ]
+        }"#).unwrap();
+        let err = ApicError::from_response(&body, StatusCode::BAD_REQUEST).unwrap();
+        assert_eq!(err.code(), ApicError::OBJECT_NOT_FOUND);
+        assert_eq!(err.text(), "Unable to find the object");
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.dn(), Some("uni/tn-x"));
+
+        let body = json::parse(r#"{
+            "totalCount": "1",
+            "imdata": [
+                {"error": {"attributes": {"code": "400", "text": "invalid property value"}}}
+            ]
+        }"#).unwrap();
+        let err = ApicError::from_response(&body, StatusCode::BAD_REQUEST).unwrap();
+        assert_eq!(err.code(), ApicError::INVALID_ARGUMENT);
+        assert_eq!(err.dn(), None);
+
+        let body = json::parse(r#"{"totalCount": "0", "imdata": []}"#).unwrap();
+        assert_eq!(ApicError::from_response(&body, StatusCode::BAD_GATEWAY), None);
+    }
+
+    fn make_result(objects: Vec<AciObject>, total_count: Option<u64>) -> QueryResult {
+        QueryResult {
+            objects,
+            total_count,
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn query_result_metadata() {
+        let body = json::parse(r#"{
+            "totalCount": "3",
+            "imdata": [
+                {"fvTenant": {"attributes": {"dn": "uni/tn-a"}}},
+                {"fvTenant": {"attributes": {"dn": "uni/tn-b"}}}
+            ]
+        }"#).unwrap();
+        let result = json_to_query_result(body).unwrap();
+        assert_eq!(result.objects().len(), 2);
+        assert_eq!(result.total_count(), Some(3));
+        assert!(!result.is_complete());
+        assert_eq!(result.metadata().len(), 1);
+        assert_eq!(result.metadata()["totalCount"], "3");
+        assert_eq!(result.mo_count(), None);
+    }
+
+    #[test]
+    fn query_result_mo_count() {
+        let body = json::parse(r#"{
+            "totalCount": "1",
+            "imdata": [
+                {"moCount": {"attributes": {"childAction": "", "count": "42", "dn": "", "status": ""}}}
+            ]
+        }"#).unwrap();
+        let result = json_to_query_result(body).unwrap();
+        assert!(result.is_complete());
+        assert_eq!(result.mo_count(), Some(42));
+    }
+
+    #[tokio::test]
+    async fn paged_stream_stops_at_total_count() {
+        let mut requested_pages = Vec::new();
+        let objects: Vec<AciObject> = paged_stream(NonZeroU64::new(2).unwrap(), |page| {
+            requested_pages.push(page);
+            async move { Ok(make_result(make_objects(page * 2, 2), Some(6))) }
+        })
+            .map(|r| r.unwrap())
+            .collect()
+            .await;
+        assert_eq!(objects, make_objects(0, 6));
+        assert_eq!(requested_pages, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn paged_stream_stops_at_short_page() {
+        let objects: Vec<AciObject> = paged_stream(NonZeroU64::new(2).unwrap(), |page| async move {
+            let count = if page < 2 { 2 } else { 1 };
+            Ok(make_result(make_objects(page * 2, count), None))
+        })
+            .map(|r| r.unwrap())
+            .collect()
+            .await;
+        assert_eq!(objects, make_objects(0, 5));
+    }
+
+    #[tokio::test]
+    async fn paged_stream_ends_after_error() {
+        let results: Vec<Result<AciObject, ApicCommError>> = paged_stream(NonZeroU64::new(2).unwrap(), |page| async move {
+            if page == 0 {
+                Ok(make_result(make_objects(0, 2), None))
+            } else {
+                Err(ApicCommError::Timeout)
+            }
+        })
+            .collect()
+            .await;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(matches!(results[2], Err(ApicCommError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn login_again_after_session_rejection() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let server_queries = queries.clone();
+        let (addr, logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/class/fvTenant.json" => {
+                    if server_queries.fetch_add(1, Ordering::SeqCst) == 0 {
+                        let body = r#"{"totalCount":"1","imdata":[{"error":{"attributes":{"code":"403","text":"Token was invalid (Error: Token timeout)"}}}]}"#;
+                        (StatusCode::FORBIDDEN, String::from(body))
+                    } else {
+                        (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = test_server::connect(addr).await;
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+
+        let objects = conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap();
+        assert_eq!(objects.len(), 0);
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn login_again_after_unauthorized_response() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let server_queries = queries.clone();
+        let (addr, logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/class/fvTenant.json" => {
+                    if server_queries.fetch_add(1, Ordering::SeqCst) == 0 {
+                        let body = r#"{"totalCount":"1","imdata":[{"error":{"attributes":{"code":"401","text":"Need a valid webtoken cookie (named APIC-Cookie) or a signed request with signature in the cookie."}}}]}"#;
+                        (StatusCode::UNAUTHORIZED, String::from(body))
+                    } else {
+                        (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = test_server::connect(addr).await;
+        let objects = conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap();
+        assert_eq!(objects.len(), 0);
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_only_once_after_session_rejection() {
+        let (addr, _logins) = test_server::serve_apic(|_req| {
+            let body = r#"{"totalCount":"1","imdata":[{"error":{"attributes":{"code":"403","text":"Token was invalid"}}}]}"#;
+            (StatusCode::FORBIDDEN, String::from(body))
+        });
+
+        let conn = test_server::connect(addr).await;
+        let err = conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap_err();
+        assert!(err.is_session_rejection());
+    }
+
+    #[tokio::test]
+    async fn retry_policy() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let posts = Arc::new(AtomicUsize::new(0));
+        let server_queries = queries.clone();
+        let server_posts = posts.clone();
+        let (addr, _logins) = test_server::serve_apic(move |req| {
+            match (req.method().as_str(), req.uri().path()) {
+                ("GET", "/api/class/fvTenant.json") => {
+                    // fail twice, then succeed
+                    if server_queries.fetch_add(1, Ordering::SeqCst) % 3 < 2 {
+                        (StatusCode::SERVICE_UNAVAILABLE, String::from("{}"))
+                    } else {
+                        (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                ("POST", "/api/mo/uni%2Ftn-0.json") => {
+                    server_posts.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::SERVICE_UNAVAILABLE, String::from("{}"))
+                },
+                (_, other) => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = test_server::connect(addr).await;
+        let fast_policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(10));
+
+        // no retries by default
+        let err = conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(queries.swap(0, Ordering::SeqCst), 1);
+
+        // per-call policy
+        let objects = conn.get_instances(
+            "fvTenant",
+            QuerySettings::new().retry_policy(fast_policy.clone()),
+        ).await.unwrap();
+        assert_eq!(objects.len(), 0);
+        assert_eq!(queries.swap(0, Ordering::SeqCst), 3);
+
+        // connection policy with too few attempts
+        conn.set_retry_policy(fast_policy.clone().max_attempts(2)).await;
+        let err = conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(queries.swap(0, Ordering::SeqCst), 2);
+
+        // POST is never retried
+        conn.set_retry_policy(fast_policy).await;
+        let err = conn.post_object(&make_objects(0, 1)[0]).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(posts.load(Ordering::SeqCst), 1);
+    }
+
+    const HTML_UNAVAILABLE: &str = "<html><body><h1>503 Service Unavailable</h1></body></html>";
+
+    #[tokio::test]
+    async fn html_error_response() {
+        let addr = test_server::serve(|_req| (StatusCode::SERVICE_UNAVAILABLE, String::from(HTML_UNAVAILABLE)));
+        let err = perform_json_request(
+            &*default_transport(),
+            Url::parse(&format!("http://{}/api/class/fvTenant.json", addr)).unwrap(),
+            "GET",
+            &HashMap::new(),
+            None,
+            Duration::from_secs(5),
+        ).await.unwrap_err();
+        match &err {
+            ApicCommError::ErrorResponse(body, parts) => {
+                assert_eq!(body.as_str(), Some(HTML_UNAVAILABLE));
+                assert_eq!(parts.status, StatusCode::SERVICE_UNAVAILABLE);
+            },
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(err.is_transient());
+        assert!(RetryPolicy::new().should_retry(&err, 1));
+    }
+
+    #[tokio::test]
+    async fn rate_limiting() {
+        const THROTTLE_BACKOFF: Duration = Duration::from_secs(60);
+
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let server_queries = queries.clone();
+        let (addr, _logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/class/fvTenant.json" => {
+                    let mut queries = server_queries.lock().unwrap();
+                    queries.push(Instant::now());
+                    if queries.len() == 1 {
+                        (StatusCode::SERVICE_UNAVAILABLE, String::from(HTML_UNAVAILABLE))
+                    } else {
+                        (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = Arc::new(test_server::connect(addr).await);
+        let limiter = RateLimiter::builder(1000.0, 10)
+            .max_concurrent_requests(2)
+            .throttle_backoff(THROTTLE_BACKOFF, THROTTLE_BACKOFF * 2)
+            .build();
+        conn.set_rate_limiter(Some(limiter.clone())).await;
+        assert_eq!(conn.rate_limiter().await.unwrap().max_concurrent(), Some(2));
+
+        // the retry waits for the throttle backoff instead of the (shorter) retry backoff
+        let retry_policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(1));
+        let task_conn = conn.clone();
+        let query = tokio::spawn(async move {
+            task_conn.get_instances("fvTenant", QuerySettings::new().retry_policy(retry_policy)).await
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tokio::time::timeout(Duration::from_secs(0), limiter.acquire()).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.expect("throttling has not been reported to the rate limiter");
+        assert_eq!(queries.lock().unwrap().len(), 1);
+
+        test_server::skip_time(THROTTLE_BACKOFF).await;
+        query.await.unwrap().unwrap();
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries.len(), 2);
+        assert!(queries[1] - queries[0] >= THROTTLE_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        let unexpected = Arc::new(AtomicUsize::new(0));
+        let server_unexpected = unexpected.clone();
+        let handler = move |req: &Request<Body>| {
+            let headers = req.headers();
+            if headers.get("User-Agent").map(|v| v.as_bytes()) != Some(b"collector/1.0")
+                    || headers.get("X-Request-Source").map(|v| v.as_bytes()) != Some(b"test") {
+                server_unexpected.fetch_add(1, Ordering::SeqCst);
+            }
+            match req.uri().path() {
+                "/api/aaaLogin.json" => (StatusCode::OK, String::from(LOGIN_RESPONSE)),
+                "/api/class/fvTenant.json" => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+                other => panic!("unexpected request for {}", other),
+            }
+        };
+        let addr = test_server::serve_tls(handler);
+
+        let conn = ApicConnection::builder(
+            Url::parse(&format!("https://localhost:{}/", addr.port())).unwrap(),
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+        )
+            .timeout(Duration::from_secs(5))
+            .login_timeout(Duration::from_secs(10))
+            .connect_timeout(Duration::from_secs(1))
+            .tls_options(TlsOptions::new().add_ca_certificate_pem(test_server::TEST_CA_CERTIFICATE_PEM.as_bytes()))
+            .user_agent("collector/1.0")
+            .header("X-Request-Source", "test")
+            .prefer_http2(true)
+            .build()
+            .await.unwrap();
+        assert_eq!(conn.timeout(), Duration::from_secs(5));
+        assert_eq!(conn.login_timeout(), Duration::from_secs(10));
+        conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap();
+        assert_eq!(unexpected.load(Ordering::SeqCst), 0);
+
+        let err = ApicConnection::builder(
+            Url::parse(&format!("https://localhost:{}/", addr.port())).unwrap(),
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+        )
+            .header("X-Invalid", "line\nbreak")
+            .build()
+            .await.unwrap_err();
+        assert!(matches!(err, ApicCommError::ErrorAssemblingRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn shared_connection() {
+        let (addr, _logins) = test_server::serve_apic(|req| {
+            match req.uri().path() {
+                "/api/aaaRefresh.json" => (StatusCode::OK, String::from(LOGIN_RESPONSE)),
+                "/api/class/fvTenant.json" => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = Arc::new(test_server::connect(addr).await);
+        let login_before = conn.last_login().await;
+
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let task_conn = conn.clone();
+            tasks.push(tokio::spawn(async move {
+                task_conn.refresh().await.unwrap();
+                task_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap()
+            }));
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap().len(), 0);
+        }
+        assert!(conn.last_login().await >= login_before);
+    }
+
+    #[tokio::test]
+    async fn refresh_only_once_when_needed() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let server_refreshes = refreshes.clone();
+        let (addr, _logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/aaaRefresh.json" => {
+                    server_refreshes.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::OK, String::from(LOGIN_RESPONSE))
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = Arc::new(test_server::connect(addr).await);
+        conn.refresh_if_needed().await.unwrap();
+        assert_eq!(refreshes.load(Ordering::SeqCst), 0);
+
+        // the session times out after 600s and is refreshed a minute before
+        test_server::skip_time(Duration::from_secs(541)).await;
+        assert!(conn.should_refresh_login().await);
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let task_conn = conn.clone();
+            tasks.push(tokio::spawn(async move {
+                task_conn.refresh_if_needed().await.unwrap();
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        assert!(!conn.should_refresh_login().await);
+    }
+
+    #[tokio::test]
+    async fn keep_alive() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let server_refreshes = refreshes.clone();
+        let (addr, logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/aaaRefresh.json" => {
+                    if server_refreshes.fetch_add(1, Ordering::SeqCst) == 0 {
+                        (StatusCode::OK, String::from(LOGIN_RESPONSE))
+                    } else {
+                        (StatusCode::INTERNAL_SERVER_ERROR, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = Arc::new(test_server::connect(addr).await);
+        let keep_alive = conn.start_keep_alive().await;
+
+        // the first refresh succeeds, the second falls back to logging in
+        for expected_logins in &[1, 2] {
+            let login_before = conn.last_login().await;
+            test_server::skip_time(Duration::from_secs(541)).await;
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while conn.last_login().await == login_before {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }).await.expect("keep-alive task did not renew the session");
+            assert_eq!(logins.load(Ordering::SeqCst), *expected_logins);
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(1), keep_alive).await
+            .expect("keep-alive task did not stop")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn logout() {
+        let logouts = Arc::new(AtomicUsize::new(0));
+        let server_logouts = logouts.clone();
+        let (addr, _logins) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/aaaLogout.json" => {
+                    server_logouts.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        // explicit logout disables logout on drop
+        let conn = test_server::connect(addr).await;
+        conn.set_logout_on_drop(true).await;
+        conn.logout().await.unwrap();
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(logouts.load(Ordering::SeqCst), 1);
+
+        // logout on drop
+        let conn = test_server::connect(addr).await;
+        conn.set_logout_on_drop(true).await;
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(logouts.load(Ordering::SeqCst), 2);
+
+        // no logout on drop by default
+        let conn = test_server::connect(addr).await;
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(logouts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn login_lazily_after_logout() {
+        let (addr, logins) = test_server::serve_apic(|req| {
+            match req.uri().path() {
+                "/api/aaaLogout.json" => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+                "/api/class/fvTenant.json" => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+
+        let conn = test_server::connect(addr).await;
+        conn.logout().await.unwrap();
+        assert_eq!(conn.last_login().await, None);
+        assert_eq!(*conn.session().await.auth_data(), Default::default());
+        assert!(matches!(conn.refresh().await, Err(ApicCommError::NoSession)));
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+
+        conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap();
+        assert!(conn.last_login().await.is_some());
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resume_session() {
+        let logins = Arc::new(AtomicUsize::new(0));
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let server_logins = logins.clone();
+        let server_refreshes = refreshes.clone();
+        let addr = test_server::serve(move |req| {
+            match req.uri().path() {
+                "/api/aaaLogin.json" => {
+                    server_logins.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::OK, String::from(LOGIN_RESPONSE))
+                },
+                "/api/aaaRefresh.json" => {
+                    server_refreshes.fetch_add(1, Ordering::SeqCst);
+                    let cookie = req.headers().get("Cookie")
+                        .and_then(|c| c.to_str().ok())
+                        .unwrap_or("");
+                    if cookie == "APIC-cookie=TOKEN" {
+                        (StatusCode::OK, String::from(LOGIN_RESPONSE))
+                    } else {
+                        (StatusCode::FORBIDDEN, String::from(EMPTY_RESPONSE))
+                    }
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+        let uri = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let auth = ApicUsernamePasswordAuth::new("admin".into(), "password".into());
+        let timeout = Duration::from_secs(5);
+
+        let conn = ApicConnection::new(uri.clone(), auth.clone(), timeout).await.unwrap();
+        let session = conn.session().await;
+        assert_eq!(session.base_uri(), &uri);
+        assert_eq!(session.auth_data().apic_cookie(), "TOKEN");
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+
+        let resume = |session: ApicSession| {
+            ApicConnection::builder(uri.clone(), auth.clone())
+                .timeout(timeout)
+                .session(session)
+                .build()
+        };
+
+        // a valid session is refreshed instead of logging in
+        resume(session.clone()).await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+
+        // a rejected session falls back to logging in
+        let stale = ApicSession::new(
+            uri.clone(),
+            ApicAuthenticatorData::new("STALE".into(), None, Duration::from_secs(600)),
+            SystemTime::now(),
+        );
+        let conn = resume(stale).await.unwrap();
+        assert_eq!(conn.session().await.auth_data().apic_cookie(), "TOKEN");
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+
+        // an expired session is not even tried
+        let expired = ApicSession::new(
+            uri.clone(),
+            session.auth_data().clone(),
+            SystemTime::now() - Duration::from_secs(601),
+        );
+        resume(expired).await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 3);
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+
+        // a missing cache file leads to logging in, after which the session is cached and resumed
+        let cache_path = std::env::temp_dir()
+            .join(format!("aci-session-cache-test-{}.json", std::process::id()));
+        let cached = || {
+            ApicConnection::builder(uri.clone(), auth.clone())
+                .timeout(timeout)
+                .session_cache(&cache_path)
+                .build()
+        };
+        cached().await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 4);
+        cached().await.unwrap();
+        std::fs::remove_file(&cache_path).unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 4);
+        assert_eq!(refreshes.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn dynamic_authenticator() {
+        let (addr, _) = test_server::serve_apic(|req| {
+            match req.uri().path() {
+                "/api/class/fvTenant.json" => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+        let uri = test_server::base_uri(addr);
+
+        let auth: Box<dyn ApicAuthenticator> = Box::new(
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into())
+        );
+        let conn = ApicConnection::new(uri.clone(), auth, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+
+        let shared_auth: Arc<dyn ApicAuthenticator> = Arc::new(
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into())
+        );
+        let multi_conn = ApicMultiConnection::new(vec![uri], shared_auth, Duration::from_secs(5))
+            .await.unwrap();
+        assert_eq!(multi_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        #[derive(Debug, Default)]
+        struct MockTransport {
+            paths: std::sync::Mutex<Vec<String>>,
+        }
+        #[async_trait]
+        impl HttpTransport for MockTransport {
+            async fn request(&self, request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+                let path = String::from(request.uri().path());
+                let body = match path.as_str() {
+                    "/api/aaaLogin.json" => LOGIN_RESPONSE,
+                    _ => EMPTY_RESPONSE,
+                };
+                self.paths.lock().unwrap().push(path);
+                Ok(Response::new(Body::from(body)))
+            }
+        }
+
+        let transport = Arc::new(MockTransport::default());
+        let conn = ApicConnection::builder(
+            Url::parse("https://apic.invalid/").unwrap(),
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+        )
+            .transport(transport.clone())
+            .build()
+            .await.unwrap();
+        assert_eq!(conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+        assert_eq!(
+            *transport.paths.lock().unwrap(),
+            vec![String::from("/api/aaaLogin.json"), String::from("/api/class/fvTenant.json")],
+        );
+    }
+}
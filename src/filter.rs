@@ -0,0 +1,586 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+
+/// A filter expression that restricts which managed objects are returned by a query.
+///
+/// Properties are specified in the form `className.propertyName`, e.g. `fvTenant.name`. Values are
+/// always rendered as quoted strings.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum FilterExpression {
+    /// The property must be equal to the value (`eq`).
+    Equal(String, String),
+
+    /// The property must not be equal to the value (`ne`).
+    NotEqual(String, String),
+
+    /// The property must be less than the value (`lt`).
+    LessThan(String, String),
+
+    /// The property must be greater than the value (`gt`).
+    GreaterThan(String, String),
+
+    /// The property must be less than or equal to the value (`le`).
+    LessOrEqual(String, String),
+
+    /// The property must be greater than or equal to the value (`ge`).
+    GreaterOrEqual(String, String),
+
+    /// The property must lie between the two values, inclusive (`bw`).
+    Between(String, String, String),
+
+    /// The property must match the given regular expression (`wcard`).
+    Wildcard(String, String),
+
+    /// The bitmask property must have at least one of the given bits set (`anybit`).
+    AnyBit(String, String),
+
+    /// The bitmask property must have all of the given bits set (`allbits`).
+    AllBits(String, String),
+
+    /// All the subexpressions must match (`and`).
+    And(FilterOperands),
+
+    /// At least one of the subexpressions must match (`or`).
+    Or(FilterOperands),
+
+    /// The subexpression must not match (`not`).
+    Not(Box<FilterExpression>),
+}
+impl FilterExpression {
+    /// Creates a filter expression matching objects whose property equals the value.
+    pub fn equal<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::Equal(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property does not equal the value.
+    pub fn not_equal<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::NotEqual(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property is less than the value.
+    pub fn less_than<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::LessThan(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property is greater than the value.
+    pub fn greater_than<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::GreaterThan(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property is less than or equal to the
+    /// value.
+    pub fn less_or_equal<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::LessOrEqual(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property is greater than or equal to the
+    /// value.
+    pub fn greater_or_equal<P: Into<String>, V: Into<String>>(property: P, value: V) -> Self {
+        FilterExpression::GreaterOrEqual(property.into(), value.into())
+    }
+
+    /// Creates a filter expression matching objects whose property lies between the two values
+    /// (inclusive).
+    pub fn between<P: Into<String>, V: Into<String>, W: Into<String>>(property: P, low: V, high: W) -> Self {
+        FilterExpression::Between(property.into(), low.into(), high.into())
+    }
+
+    /// Creates a filter expression matching objects whose property matches the regular expression.
+    pub fn wildcard<P: Into<String>, V: Into<String>>(property: P, pattern: V) -> Self {
+        FilterExpression::Wildcard(property.into(), pattern.into())
+    }
+
+    /// Creates a filter expression matching objects whose bitmask property has at least one of the
+    /// given bits set.
+    pub fn any_bit<P: Into<String>, V: Into<String>>(property: P, bits: V) -> Self {
+        FilterExpression::AnyBit(property.into(), bits.into())
+    }
+
+    /// Creates a filter expression matching objects whose bitmask property has all of the given
+    /// bits set.
+    pub fn all_bits<P: Into<String>, V: Into<String>>(property: P, bits: V) -> Self {
+        FilterExpression::AllBits(property.into(), bits.into())
+    }
+
+    /// Creates a filter expression matching objects that match all the given subexpressions.
+    ///
+    /// Returns `None` if no subexpressions are given, as the APIC does not accept an empty `and`.
+    pub fn and<I: IntoIterator<Item = FilterExpression>>(subexpressions: I) -> Option<Self> {
+        FilterOperands::new(subexpressions).map(FilterExpression::And)
+    }
+
+    /// Creates a filter expression matching objects that match at least one of the given
+    /// subexpressions.
+    ///
+    /// Returns `None` if no subexpressions are given, as the APIC does not accept an empty `or`.
+    pub fn or<I: IntoIterator<Item = FilterExpression>>(subexpressions: I) -> Option<Self> {
+        FilterOperands::new(subexpressions).map(FilterExpression::Or)
+    }
+
+    /// Creates a filter expression matching objects that do not match the given subexpression.
+    pub fn negate(subexpression: FilterExpression) -> Self {
+        FilterExpression::Not(Box::new(subexpression))
+    }
+
+    /// Returns the name of the operator of this filter expression as used in the REST API.
+    pub fn operator(&self) -> &'static str {
+        match self {
+            FilterExpression::Equal(_, _) => "eq",
+            FilterExpression::NotEqual(_, _) => "ne",
+            FilterExpression::LessThan(_, _) => "lt",
+            FilterExpression::GreaterThan(_, _) => "gt",
+            FilterExpression::LessOrEqual(_, _) => "le",
+            FilterExpression::GreaterOrEqual(_, _) => "ge",
+            FilterExpression::Between(_, _, _) => "bw",
+            FilterExpression::Wildcard(_, _) => "wcard",
+            FilterExpression::AnyBit(_, _) => "anybit",
+            FilterExpression::AllBits(_, _) => "allbits",
+            FilterExpression::And(_) => "and",
+            FilterExpression::Or(_) => "or",
+            FilterExpression::Not(_) => "not",
+        }
+    }
+}
+impl fmt::Display for FilterExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.operator())?;
+        match self {
+            FilterExpression::Equal(p, v)
+            | FilterExpression::NotEqual(p, v)
+            | FilterExpression::LessThan(p, v)
+            | FilterExpression::GreaterThan(p, v)
+            | FilterExpression::LessOrEqual(p, v)
+            | FilterExpression::GreaterOrEqual(p, v)
+            | FilterExpression::Wildcard(p, v)
+            | FilterExpression::AnyBit(p, v)
+            | FilterExpression::AllBits(p, v) => {
+                write!(f, "{},", p)?;
+                write_quoted(f, v)?;
+            },
+            FilterExpression::Between(p, low, high) => {
+                write!(f, "{},", p)?;
+                write_quoted(f, low)?;
+                write!(f, ",")?;
+                write_quoted(f, high)?;
+            },
+            FilterExpression::And(subs) | FilterExpression::Or(subs) => {
+                let mut first = true;
+                for sub in subs.iter() {
+                    if first {
+                        first = false;
+                    } else {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", sub)?;
+                }
+            },
+            FilterExpression::Not(sub) => {
+                write!(f, "{}", sub)?;
+            },
+        }
+        write!(f, ")")
+    }
+}
+impl FromStr for FilterExpression {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_filter(s)
+    }
+}
+
+/// The subexpressions of an `and` or `or` filter expression.
+///
+/// The APIC requires at least one subexpression, so an empty list of operands cannot be
+/// constructed.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FilterOperands(Vec<FilterExpression>);
+impl FilterOperands {
+    /// Collects the given subexpressions into a list of operands.
+    ///
+    /// Returns `None` if no subexpressions are given.
+    pub fn new<I: IntoIterator<Item = FilterExpression>>(subexpressions: I) -> Option<Self> {
+        let subs: Vec<FilterExpression> = subexpressions.into_iter().collect();
+        if subs.is_empty() {
+            None
+        } else {
+            Some(FilterOperands(subs))
+        }
+    }
+
+    /// Returns the subexpressions as a slice, which is never empty.
+    pub fn as_slice(&self) -> &[FilterExpression] {
+        &self.0
+    }
+
+    /// Returns an iterator over the subexpressions.
+    pub fn iter(&self) -> std::slice::Iter<'_, FilterExpression> {
+        self.0.iter()
+    }
+
+    /// Returns the subexpressions as a vector, which is never empty.
+    pub fn into_vec(self) -> Vec<FilterExpression> {
+        self.0
+    }
+}
+impl<'a> IntoIterator for &'a FilterOperands {
+    type Item = &'a FilterExpression;
+    type IntoIter = std::slice::Iter<'a, FilterExpression>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+
+/// Writes a value as a double-quoted string, escaping double quotes and backslashes.
+fn write_quoted(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    write!(f, "\"")
+}
+
+
+/// An error returned if parsing a filter expression has been unsuccessful.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ParseFilterError {
+    /// The filter expression ended unexpectedly.
+    UnexpectedEnd,
+
+    /// An unexpected character was encountered at the given byte position.
+    UnexpectedCharacter(char, usize),
+
+    /// The operator starting at the given byte position is not known.
+    UnknownOperator(String, usize),
+
+    /// The operator starting at the given byte position has received the wrong number of
+    /// arguments.
+    WrongArgumentCount(String, usize),
+
+    /// Additional characters follow the filter expression, starting at the given byte position.
+    TrailingCharacters(usize),
+}
+impl fmt::Display for ParseFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            ParseFilterError::UnexpectedEnd
+                => write!(f, "unexpected end of filter expression"),
+            ParseFilterError::UnexpectedCharacter(c, pos)
+                => write!(f, "unexpected character {:?} at byte position {}", c, pos),
+            ParseFilterError::UnknownOperator(op, pos)
+                => write!(f, "unknown operator {:?} at byte position {}", op, pos),
+            ParseFilterError::WrongArgumentCount(op, pos)
+                => write!(f, "wrong number of arguments to operator {:?} at byte position {}", op, pos),
+            ParseFilterError::TrailingCharacters(pos)
+                => write!(f, "trailing characters at byte position {}", pos),
+        }
+    }
+}
+impl Error for ParseFilterError {
+}
+
+
+/// Parses a filter expression in REST API syntax, e.g. `and(eq(fvTenant.name,"x"),wcard(fvAEPg.dn,"web"))`.
+///
+/// Values may be double-quoted (with backslash escapes for double quotes and backslashes) or
+/// unquoted; whitespace between tokens is ignored.
+pub fn parse_filter(filter: &str) -> Result<FilterExpression, ParseFilterError> {
+    let mut parser = FilterParser { text: filter, pos: 0 };
+    let expr = parser.parse_expression()?;
+    parser.skip_whitespace();
+    if parser.pos < filter.len() {
+        return Err(ParseFilterError::TrailingCharacters(parser.pos));
+    }
+    Ok(expr)
+}
+
+
+/// An argument to a filter operator.
+enum FilterArgument {
+    Expression(FilterExpression),
+    Value(String),
+}
+
+
+struct FilterParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+impl<'a> FilterParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseFilterError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(ParseFilterError::UnexpectedEnd),
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            },
+            Some(c) => Err(ParseFilterError::UnexpectedCharacter(c, self.pos)),
+        }
+    }
+
+    /// Reads an unquoted token, which ends at whitespace, a comma, or a parenthesis.
+    fn read_token(&mut self) -> &'a str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ',' || c == '(' || c == ')' || c == '"' {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn read_quoted(&mut self) -> Result<String, ParseFilterError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        let mut escaped = false;
+        loop {
+            let c = match self.peek() {
+                None => return Err(ParseFilterError::UnexpectedEnd),
+                Some(c) => c,
+            };
+            self.pos += c.len_utf8();
+            if escaped {
+                value.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                return Ok(value);
+            } else {
+                value.push(c);
+            }
+        }
+    }
+
+    fn parse_argument(&mut self) -> Result<FilterArgument, ParseFilterError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(ParseFilterError::UnexpectedEnd),
+            Some('"') => Ok(FilterArgument::Value(self.read_quoted()?)),
+            Some(c) if c == ',' || c == '(' || c == ')' => Err(ParseFilterError::UnexpectedCharacter(c, self.pos)),
+            Some(_) => {
+                let start = self.pos;
+                let token = self.read_token();
+                self.skip_whitespace();
+                if self.peek() == Some('(') {
+                    // it's a subexpression; rewind and parse it as such
+                    self.pos = start;
+                    Ok(FilterArgument::Expression(self.parse_expression()?))
+                } else {
+                    Ok(FilterArgument::Value(String::from(token)))
+                }
+            },
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<FilterExpression, ParseFilterError> {
+        self.skip_whitespace();
+        let op_pos = self.pos;
+        let op = self.read_token();
+        if op.is_empty() {
+            return match self.peek() {
+                None => Err(ParseFilterError::UnexpectedEnd),
+                Some(c) => Err(ParseFilterError::UnexpectedCharacter(c, self.pos)),
+            };
+        }
+        self.expect('(')?;
+
+        let mut args = Vec::new();
+        loop {
+            args.push(self.parse_argument()?);
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Err(ParseFilterError::UnexpectedEnd),
+                Some(',') => {
+                    self.pos += 1;
+                },
+                Some(')') => {
+                    self.pos += 1;
+                    break;
+                },
+                Some(c) => return Err(ParseFilterError::UnexpectedCharacter(c, self.pos)),
+            }
+        }
+
+        let wrong_count = || ParseFilterError::WrongArgumentCount(String::from(op), op_pos);
+
+        match op {
+            "and" | "or" | "not" => {
+                let mut subs = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg {
+                        FilterArgument::Expression(e) => subs.push(e),
+                        FilterArgument::Value(_) => return Err(wrong_count()),
+                    }
+                }
+                match op {
+                    // the argument loop has read at least one argument
+                    "and" => Ok(FilterExpression::And(FilterOperands(subs))),
+                    "or" => Ok(FilterExpression::Or(FilterOperands(subs))),
+                    _ => {
+                        if subs.len() != 1 {
+                            return Err(wrong_count());
+                        }
+                        Ok(FilterExpression::Not(Box::new(subs.remove(0))))
+                    },
+                }
+            },
+            "eq" | "ne" | "lt" | "gt" | "le" | "ge" | "bw" | "wcard" | "anybit" | "allbits" => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg {
+                        FilterArgument::Value(v) => values.push(v),
+                        FilterArgument::Expression(_) => return Err(wrong_count()),
+                    }
+                }
+                let expected_count = if op == "bw" { 3 } else { 2 };
+                if values.len() != expected_count {
+                    return Err(wrong_count());
+                }
+                let mut values_iter = values.into_iter();
+                let prop = values_iter.next().unwrap();
+                let value = values_iter.next().unwrap();
+                Ok(match op {
+                    "eq" => FilterExpression::Equal(prop, value),
+                    "ne" => FilterExpression::NotEqual(prop, value),
+                    "lt" => FilterExpression::LessThan(prop, value),
+                    "gt" => FilterExpression::GreaterThan(prop, value),
+                    "le" => FilterExpression::LessOrEqual(prop, value),
+                    "ge" => FilterExpression::GreaterOrEqual(prop, value),
+                    "bw" => FilterExpression::Between(prop, value, values_iter.next().unwrap()),
+                    "wcard" => FilterExpression::Wildcard(prop, value),
+                    "anybit" => FilterExpression::AnyBit(prop, value),
+                    _ => FilterExpression::AllBits(prop, value),
+                })
+            },
+            _ => Err(ParseFilterError::UnknownOperator(String::from(op), op_pos)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_simple() {
+        let expr = FilterExpression::equal("fvTenant.name", "x");
+        assert_eq!(expr.to_string(), "eq(fvTenant.name,\"x\")");
+    }
+
+    #[test]
+    fn render_nested() {
+        let expr = FilterExpression::and(vec![
+            FilterExpression::equal("fvTenant.name", "x"),
+            FilterExpression::wildcard("fvAEPg.dn", "web"),
+        ]).unwrap();
+        assert_eq!(expr.to_string(), "and(eq(fvTenant.name,\"x\"),wcard(fvAEPg.dn,\"web\"))");
+    }
+
+    #[test]
+    fn empty_operands() {
+        assert_eq!(FilterExpression::and(vec![]), None);
+        assert_eq!(FilterExpression::or(Vec::new()), None);
+        assert_eq!(FilterOperands::new(vec![]), None);
+        assert_eq!(
+            parse_filter("and()").unwrap_err(),
+            ParseFilterError::UnexpectedCharacter(')', 4),
+        );
+    }
+
+    #[test]
+    fn render_between_and_not() {
+        let expr = FilterExpression::negate(
+            FilterExpression::between("faultInst.created", "2021-01-01", "2021-02-01"),
+        );
+        assert_eq!(expr.to_string(), "not(bw(faultInst.created,\"2021-01-01\",\"2021-02-01\"))");
+    }
+
+    #[test]
+    fn render_escapes() {
+        let expr = FilterExpression::equal("fvTenant.descr", "say \"hi\" \\o/");
+        assert_eq!(expr.to_string(), "eq(fvTenant.descr,\"say \\\"hi\\\" \\\\o/\")");
+    }
+
+    #[test]
+    fn parse_nested() {
+        let expr = parse_filter("and(eq(fvTenant.name,\"x\"),wcard(fvAEPg.dn,\"web\"))").unwrap();
+        assert_eq!(
+            expr,
+            FilterExpression::and(vec![
+                FilterExpression::equal("fvTenant.name", "x"),
+                FilterExpression::wildcard("fvAEPg.dn", "web"),
+            ]).unwrap(),
+        );
+    }
+
+    #[test]
+    fn parse_whitespace_and_unquoted() {
+        let expr: FilterExpression = " or( gt(faultInst.severity, major) , not(anybit(fvAEPg.configFlags,\"a\")) ) "
+            .parse().unwrap();
+        assert_eq!(
+            expr,
+            FilterExpression::or(vec![
+                FilterExpression::greater_than("faultInst.severity", "major"),
+                FilterExpression::negate(FilterExpression::any_bit("fvAEPg.configFlags", "a")),
+            ]).unwrap(),
+        );
+    }
+
+    #[test]
+    fn roundtrip_escapes() {
+        let expr = FilterExpression::between("a.b", "\"q\"", "c:\\d,e)");
+        let parsed = parse_filter(&expr.to_string()).unwrap();
+        assert_eq!(parsed, expr);
+    }
+
+    #[test]
+    fn unknown_operator() {
+        let err = parse_filter("and(foo(a.b,\"c\"))").unwrap_err();
+        assert_eq!(err, ParseFilterError::UnknownOperator(String::from("foo"), 4));
+    }
+
+    #[test]
+    fn wrong_argument_count() {
+        let err = parse_filter("bw(a.b,\"c\")").unwrap_err();
+        assert_eq!(err, ParseFilterError::WrongArgumentCount(String::from("bw"), 0));
+
+        let err = parse_filter("not(eq(a.b,\"c\"),eq(a.b,\"d\"))").unwrap_err();
+        assert_eq!(err, ParseFilterError::WrongArgumentCount(String::from("not"), 0));
+    }
+
+    #[test]
+    fn unterminated() {
+        assert_eq!(parse_filter("eq(a.b,\"c").unwrap_err(), ParseFilterError::UnexpectedEnd);
+        assert_eq!(parse_filter("eq(a.b,\"c\"").unwrap_err(), ParseFilterError::UnexpectedEnd);
+    }
+
+    #[test]
+    fn trailing_characters() {
+        let err = parse_filter("eq(a.b,\"c\")x").unwrap_err();
+        assert_eq!(err, ParseFilterError::TrailingCharacters(11));
+    }
+}
@@ -1,7 +1,16 @@
 pub mod auth;
 pub mod conn;
+pub mod filter;
 pub mod multi_conn;
 pub mod path;
+pub mod proxy;
+pub mod rate_limit;
+pub mod retry;
+pub mod session;
+pub mod subscription;
+pub mod tls;
+#[cfg(test)]
+mod test_server;
 
 use std::collections::HashMap;
 use std::error::Error;
@@ -145,67 +154,67 @@ impl AciObject {
             return Err(AciObjectError::JsonObjectMultipleEntries);
         }
 
-        for (class_name, keys_values) in value.entries() {
-            let mut attribs = HashMap::new();
-            let json_attribs = &keys_values["attributes"];
-            if json_attribs.is_null() {
-                return Err(AciObjectError::JsonMissingAttributes);
-            }
+        let (class_name, keys_values) = value.entries()
+            .next()
+            .expect("object has exactly one entry");
+        let mut attribs = HashMap::new();
+        let json_attribs = &keys_values["attributes"];
+        if json_attribs.is_null() {
+            return Err(AciObjectError::JsonMissingAttributes);
+        }
 
-            let mut dn: Option<String> = None;
-            let mut rn: Option<String> = None;
+        let mut dn: Option<String> = None;
+        let mut rn: Option<String> = None;
 
-            for (key, val) in json_attribs.entries() {
-                let owned_val = String::from(val.as_str().expect("string value"));
-                if key == DN_KEY {
-                    dn = Some(owned_val.clone());
-                } else if key == RN_KEY {
-                    rn = Some(owned_val.clone());
-                }
-                attribs.insert(
-                    String::from(key),
-                    owned_val,
-                );
+        for (key, val) in json_attribs.entries() {
+            let owned_val = String::from(val.as_str().expect("string value"));
+            if key == DN_KEY {
+                dn = Some(owned_val.clone());
+            } else if key == RN_KEY {
+                rn = Some(owned_val.clone());
             }
+            attribs.insert(
+                String::from(key),
+                owned_val,
+            );
+        }
 
-            // ensure we have both "dn" and "rn" attributes
-            if dn.is_none() {
-                if let Some(pdn) = parent_dn {
-                    // try constructing DN out of parent DN and RN
-                    if let Some(rn) = attribs.get(RN_KEY) {
-                        let dn_string = format!("{}/{}", pdn, rn);
-                        dn = Some(dn_string.clone());
-                        attribs.insert(String::from(DN_KEY), dn_string);
-                    }
+        // ensure we have both "dn" and "rn" attributes
+        if dn.is_none() {
+            if let Some(pdn) = parent_dn {
+                // try constructing DN out of parent DN and RN
+                if let Some(rn) = attribs.get(RN_KEY) {
+                    let dn_string = format!("{}/{}", pdn, rn);
+                    dn = Some(dn_string.clone());
+                    attribs.insert(String::from(DN_KEY), dn_string);
                 }
-            };
-            if rn.is_none() {
-                if let Some(dn) = attribs.get(DN_KEY) {
-                    // try constructing RN out of DN
-                    if let Ok(dn_bits) = split_dn(dn) {
-                        if let Some(dn_last) = dn_bits.last() {
-                            let rn_string = String::from(*dn_last);
-                            //rn = Some(rn_string.clone());
-                            attribs.insert(String::from(RN_KEY), rn_string);
-                        }
+            }
+        };
+        if rn.is_none() {
+            if let Some(dn) = attribs.get(DN_KEY) {
+                // try constructing RN out of DN
+                if let Ok(dn_bits) = split_dn(dn) {
+                    if let Some(dn_last) = dn_bits.last() {
+                        let rn_string = String::from(*dn_last);
+                        //rn = Some(rn_string.clone());
+                        attribs.insert(String::from(RN_KEY), rn_string);
                     }
                 }
             }
+        }
 
-            let mut children = Vec::new();
-            let json_children = &keys_values["children"];
-            for json_child in json_children.members() {
-                let child = AciObject::from_json(json_child, dn.as_deref())?;
-                children.push(child);
-            }
-
-            return AciObject::new(
-                String::from(class_name),
-                attribs,
-                children,
-            );
+        let mut children = Vec::new();
+        let json_children = &keys_values["children"];
+        for json_child in json_children.members() {
+            let child = AciObject::from_json(json_child, dn.as_deref())?;
+            children.push(child);
         }
-        panic!("fell out of loop");
+
+        AciObject::new(
+            String::from(class_name),
+            attribs,
+            children,
+        )
     }
 
     /// Convert this AciObject into its JSON representation.
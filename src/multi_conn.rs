@@ -1,12 +1,23 @@
-use std::time::Duration;
+use std::num::NonZeroU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use log::{info, warn};
+use futures_util::stream::Stream;
+use hyper::header::USER_AGENT;
+use log::{debug, info, warn};
 use tokio::sync::RwLock;
 use url::Url;
 
 use crate::AciObject;
 use crate::auth::ApicAuthenticator;
-use crate::conn::{ApicCommError, ApicConnection, QuerySettings};
+use crate::conn::{
+    paged_stream, ApicCommError, ApicConnection, ConnectionSettings, HttpTransport, QueryResult,
+    QuerySettings,
+};
+use crate::proxy::ProxyOptions;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::tls::TlsOptions;
 
 
 #[derive(Debug)]
@@ -15,106 +26,225 @@ struct ApicConnectionHolder<A: ApicAuthenticator + Clone> {
     pub conn: ApicConnection<A>
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum RoundRobinRemedy {
-    Refresh,
-    Increment,
+
+/// Governs when an APIC is considered unavailable and skipped during failover.
+///
+/// Each APIC has a circuit breaker counting the consecutive failed operations (those failing with
+/// a transient or server error). Once the failure threshold is reached, the breaker opens and the
+/// APIC is skipped during failover for the given duration. Afterwards, the APIC is tried again;
+/// if that fails as well, the breaker opens again immediately, while a success closes it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CircuitBreakerPolicy {
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+impl CircuitBreakerPolicy {
+    /// Creates a new circuit breaker policy with common defaults: a breaker opens after two
+    /// consecutive failures and stays open for 30 seconds.
+    pub fn new() -> CircuitBreakerPolicy {
+        CircuitBreakerPolicy {
+            failure_threshold: 2,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets the number of consecutive failures after which a breaker opens and returns the
+    /// CircuitBreakerPolicy object. Values below 1 are treated as 1.
+    pub fn failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// Sets the duration for which an open breaker causes its APIC to be skipped and returns the
+    /// CircuitBreakerPolicy object.
+    pub fn open_duration(mut self, open_duration: Duration) -> Self {
+        self.open_duration = open_duration;
+        self
+    }
+}
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Tracks the health of a single APIC.
+#[derive(Clone, Debug, Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+impl CircuitBreaker {
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    fn record_failure(&mut self, policy: &CircuitBreakerPolicy) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= policy.failure_threshold {
+            self.open_until = Some(Instant::now() + policy.open_duration);
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open_until
+            .map(|ou| Instant::now() < ou)
+            .unwrap_or(false)
+    }
+}
+
+
+/// Returns whether an operation failing with the given error should be attempted against the next
+/// APIC.
+fn should_fail_over(error: &ApicCommError) -> bool {
+    error.is_transient() || error.is_server_error()
+}
+
+/// Returns whether a non-idempotent operation failing with the given error should be attempted
+/// against the next APIC, which is only the case if the request has never been sent because the
+/// connection could not be established.
+fn should_fail_over_unsent(error: &ApicCommError) -> bool {
+    match error {
+        ApicCommError::ErrorObtainingResponse(e) => e.is_connect(),
+        _ => false,
+    }
 }
 
 
 // FIXME: find the right combination of lifetime specifications to solve this using closures
 // (i.e. $code is a closure)
+//
+// Failed operations are attempted against the next APIC if the function passed via `@fail_over`
+// (`should_fail_over` by default) returns true for the error.
 macro_rules! round_robin_func {
     (
         $(#[$meta:meta])*
-        pub async fn $name:ident($conn:ident, $($arg:ident: $argtype:ty),*) -> $ret:ty $code:block
+        $vis:vis async fn $name:ident($conn:ident, $($arg:ident: $argtype:ty),*) -> $ret:ty $code:block
+    ) => {
+        round_robin_func! {
+            @fail_over should_fail_over;
+            $(#[$meta])*
+            $vis async fn $name($conn, $($arg: $argtype),*) -> $ret $code
+        }
+    };
+    (
+        @fail_over $fail_over:path;
+        $(#[$meta:meta])*
+        $vis:vis async fn $name:ident($conn:ident, $($arg:ident: $argtype:ty),*) -> $ret:ty $code:block
     ) => {
         $(#[$meta])*
-        pub async fn $name(&self, $($arg: $argtype,)*) -> Result<$ret, ApicCommError> {
-            let mut start_index: Option<usize> = None;
+        $vis async fn $name(&self, $($arg: $argtype,)*) -> Result<$ret, ApicCommError> {
+            let mut failures = 0;
             loop {
+                let failed_index;
+                let last_err;
+
                 // with the read lock
-                let mut remedy = {
+                {
                     let read_holder = self.cur_holder.read()
                         .await;
-                    if start_index.is_none() {
-                        start_index = Some(read_holder.index);
-                    }
+                    failed_index = read_holder.index;
 
-                    if read_holder.conn.should_refresh_login().await {
-                        RoundRobinRemedy::Refresh
-                    } else {
-                        // try performing the operation
-                        let $conn = &read_holder.conn;
-                        let op_res = $code.await;
-                        match op_res {
-                            Ok(r) => return Ok(r),
-                            Err(ApicCommError::Timeout) => RoundRobinRemedy::Increment,
-                            Err(e) => return Err(e),
-                        }
+                    // refresh the session in place if necessary
+                    let refresh_res = read_holder.conn.refresh_if_needed().await;
+                    let op_res = match refresh_res {
+                        Ok(()) => {
+                            // try performing the operation
+                            let $conn = &read_holder.conn;
+                            $code.await
+                                .map_err(|e| {
+                                    let fail_over = $fail_over(&e);
+                                    (e, fail_over)
+                                })
+                        },
+                        Err(e) => {
+                            // the operation has not been attempted yet
+                            let fail_over = should_fail_over(&e);
+                            Err((e, fail_over))
+                        },
+                    };
+                    match op_res {
+                        Ok(r) => {
+                            self.record_success(read_holder.index);
+                            return Ok(r);
+                        },
+                        Err((e, true)) => {
+                            last_err = e;
+                        },
+                        Err((e, false)) => return Err(e),
                     }
-                };
+                }
 
-                // refresh or increment is necessary
+                warn!("APIC {} is unavailable ({})", self.apic_uris[failed_index], last_err);
+                self.record_failure(failed_index);
+                failures += 1;
+                if failures >= self.apic_uris.len() {
+                    // we've given each APIC a chance
+                    return Err(last_err);
+                }
+
+                // switching to the next APIC is necessary
                 // grab the write lock
                 {
                     let mut write_holder = self.cur_holder.write()
                         .await;
-                    if remedy == RoundRobinRemedy::Refresh {
-                        match write_holder.conn.refresh().await {
-                            Ok(()) => {
-                                // retry with the current connection
-                                continue;
-                            },
-                            Err(ApicCommError::Timeout) => {
-                                // try with the next
-                                remedy = RoundRobinRemedy::Increment;
-                            },
-                            Err(e) => {
-                                // fast path out
-                                return Err(e);
-                            }
-                        }
+                    if write_holder.index != failed_index {
+                        // another operation has already switched to a different APIC; use it
+                        continue;
                     }
 
-                    if remedy == RoundRobinRemedy::Increment {
-                        loop {
-                            let cur_uri = &self.apic_uris[write_holder.index];
-                            warn!("APIC {} is unresponsive", cur_uri);
+                    let mut last_err = last_err;
+                    let mut candidate = failed_index;
+                    loop {
+                        // we have to try the next one
+                        candidate = (candidate + 1) % self.apic_uris.len();
+                        if candidate == failed_index {
+                            // we've tried them all
+                            return Err(last_err);
+                        }
 
-                            // we have to try the next one
-                            write_holder.index = (write_holder.index + 1) % self.apic_uris.len();
-                            if write_holder.index == start_index.expect("start index has a value") {
-                                // we've tried them all
-                                return Err(ApicCommError::Timeout);
-                            }
+                        let new_uri = &self.apic_uris[candidate];
+                        if self.is_circuit_open(candidate) {
+                            debug!("skipping APIC {}, which has recently been unavailable", new_uri);
+                            continue;
+                        }
+                        info!("switching to APIC {}", new_uri);
 
-                            let new_uri = &self.apic_uris[write_holder.index];
-                            info!("switching to APIC {}", new_uri);
-
-                            let new_conn_res = ApicConnection::new(
-                                new_uri.clone(),
-                                self.authenticator.clone(),
-                                self.timeout,
-                            ).await;
-                            match new_conn_res {
-                                Ok(nc) => {
-                                    write_holder.conn = nc;
-
-                                    // break out of inner loop but rerun the outer one
-                                    // (to perform the actual operation)
-                                    // we can be optimistic here because ApicConnection::new has already talked to the APIC
-                                    break;
-                                },
-                                Err(ApicCommError::Timeout) => {
-                                    // rerun the inner loop (next APIC)
-                                    continue;
+                        let mut settings = self.settings.clone();
+                        settings.retry_policy = self.retry_policy.read().await.clone();
+                        settings.rate_limiter = self.rate_limiter.read().await.clone();
+                        let new_conn_res = ApicConnection::connect(
+                            new_uri.clone(),
+                            self.transport.clone(),
+                            self.authenticator.clone(),
+                            &settings,
+                        ).await;
+                        match new_conn_res {
+                            Ok(nc) => {
+                                if *self.logout_on_drop.read().await {
+                                    nc.set_logout_on_drop(true).await;
                                 }
-                                Err(e) => {
-                                    // break out
-                                    return Err(e);
-                                },
+                                write_holder.index = candidate;
+                                write_holder.conn = nc;
+
+                                // break out of inner loop but rerun the outer one
+                                // (to perform the actual operation)
+                                // we can be optimistic here because ApicConnection::connect has already talked to the APIC
+                                break;
+                            },
+                            Err(e) if should_fail_over(&e) => {
+                                // rerun the inner loop (next APIC)
+                                warn!("APIC {} is unavailable ({})", new_uri, e);
+                                self.record_failure(candidate);
+                                last_err = e;
+                                continue;
                             }
+                            Err(e) => {
+                                // break out
+                                return Err(e);
+                            },
                         }
                     }
                 }
@@ -125,30 +255,346 @@ macro_rules! round_robin_func {
 
 
 /// An APIC connection that can fail over between multiple APICs.
+///
+/// If a request fails with a transient error (see `ApicCommError::is_transient`), e.g. because the
+/// APIC cannot be reached, or with a server error (HTTP status 5xx), the next APIC is tried. Posting
+/// objects, which is not idempotent, only fails over if the APIC cannot be reached. APICs that have
+/// repeatedly failed are skipped for a while; see `CircuitBreakerPolicy`.
 #[derive(Debug)]
 pub struct ApicMultiConnection<A: ApicAuthenticator + Clone> {
     apic_uris: Vec<Url>,
+    transport: Arc<dyn HttpTransport>,
     authenticator: A,
-    timeout: Duration,
+    settings: ConnectionSettings,
+    retry_policy: RwLock<RetryPolicy>,
+    rate_limiter: RwLock<Option<RateLimiter>>,
+    logout_on_drop: RwLock<bool>,
+    circuit_breaker_policy: CircuitBreakerPolicy,
+    circuit_breakers: Mutex<Vec<CircuitBreaker>>,
     cur_holder: RwLock<ApicConnectionHolder<A>>,
 }
-impl<A: ApicAuthenticator + Clone> ApicMultiConnection<A> {
+impl<A: ApicAuthenticator + Clone + 'static> ApicMultiConnection<A> {
     /// Creates a new ApicMultiConnection with the given APIC base URIs.
+    ///
+    /// This is a shorthand for `ApicMultiConnection::builder(apic_uris, authenticator)
+    /// .timeout(timeout).build()`.
     pub async fn new(
         apic_uris: Vec<Url>,
         authenticator: A,
         timeout: Duration,
     ) -> Result<ApicMultiConnection<A>, ApicCommError> {
+        Self::builder(apic_uris, authenticator)
+            .timeout(timeout)
+            .build()
+            .await
+    }
+
+    /// Returns a builder for an ApicMultiConnection with non-default settings.
+    pub fn builder(apic_uris: Vec<Url>, authenticator: A) -> ApicMultiConnectionBuilder<A> {
+        ApicMultiConnectionBuilder {
+            apic_uris,
+            authenticator,
+            settings: ConnectionSettings::new(),
+            circuit_breaker_policy: CircuitBreakerPolicy::new(),
+        }
+    }
+
+    /// Returns the retry policy applied to idempotent requests that do not specify their own.
+    pub async fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.read()
+            .await
+            .clone()
+    }
+
+    /// Sets the retry policy applied to idempotent requests (queries and deletions) that do not
+    /// specify their own.
+    ///
+    /// Requests are retried against the same APIC; switching to the next APIC only happens once
+    /// the retries have been exhausted. By default, requests are not retried.
+    pub async fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        // lock the current connection first, like failover does
+        let holder = self.cur_holder.read()
+            .await;
+        holder.conn.set_retry_policy(retry_policy.clone())
+            .await;
+        *self.retry_policy.write().await = retry_policy;
+    }
+
+    /// Returns the rate limiter applied to requests to the APICs, if any.
+    pub async fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.read()
+            .await
+            .clone()
+    }
+
+    /// Sets the rate limiter applied to requests to the APICs, including logins and session
+    /// refreshes.
+    ///
+    /// The same limiter is applied to the connection to each APIC, so the budget is shared between
+    /// them. By default, requests are not limited.
+    pub async fn set_rate_limiter(&self, rate_limiter: Option<RateLimiter>) {
+        // lock the current connection first, like failover does
+        let holder = self.cur_holder.read()
+            .await;
+        holder.conn.set_rate_limiter(rate_limiter.clone())
+            .await;
+        *self.rate_limiter.write().await = rate_limiter;
+    }
+
+    /// Returns the base URI of the APIC currently in use.
+    pub async fn current_apic(&self) -> Url {
+        let index = self.cur_holder.read()
+            .await
+            .index;
+        self.apic_uris[index].clone()
+    }
+
+    /// Returns whether the circuit breaker of the APIC with the given base URI is open, i.e.
+    /// whether the APIC is currently being skipped during failover.
+    pub fn is_apic_skipped(&self, apic_uri: &Url) -> bool {
+        self.apic_uris.iter()
+            .position(|u| u == apic_uri)
+            .map(|i| self.is_circuit_open(i))
+            .unwrap_or(false)
+    }
+
+    fn record_success(&self, index: usize) {
+        self.circuit_breakers.lock()
+            .expect("circuit breakers are poisoned")[index]
+            .record_success();
+    }
+
+    fn record_failure(&self, index: usize) {
+        self.circuit_breakers.lock()
+            .expect("circuit breakers are poisoned")[index]
+            .record_failure(&self.circuit_breaker_policy);
+    }
+
+    fn is_circuit_open(&self, index: usize) -> bool {
+        self.circuit_breakers.lock()
+            .expect("circuit breakers are poisoned")[index]
+            .is_open()
+    }
+
+    /// Terminates the session with the APIC currently in use.
+    ///
+    /// This also disables logging out on drop. Performing further requests logs in again.
+    pub async fn logout(&self) -> Result<(), ApicCommError> {
+        let holder = self.cur_holder.read()
+            .await;
+        *self.logout_on_drop.write().await = false;
+        holder.conn.logout()
+            .await
+    }
+
+    /// Sets whether the session with the APIC in use should be terminated when this connection is
+    /// dropped or fails over to another APIC.
+    ///
+    /// See `ApicConnection::set_logout_on_drop` for caveats.
+    pub async fn set_logout_on_drop(&self, logout_on_drop: bool) {
+        // lock the current connection first, like failover does
+        let holder = self.cur_holder.read()
+            .await;
+        holder.conn.set_logout_on_drop(logout_on_drop)
+            .await;
+        *self.logout_on_drop.write().await = logout_on_drop;
+    }
+
+    round_robin_func! {
+        /// Return instances of the given class.
+        pub async fn get_instances(conn, class_name: &str, query_settings: QuerySettings) -> Vec<AciObject> {
+            conn.get_instances(class_name, query_settings.clone())
+        }
+    }
+
+    round_robin_func! {
+        /// Return instances of the given class along with the metadata of the response.
+        pub async fn get_instances_with_metadata(conn, class_name: &str, query_settings: QuerySettings) -> QueryResult {
+            conn.get_instances_with_metadata(class_name, query_settings.clone())
+        }
+    }
+
+    /// Returns a stream of the instances of the given class, fetching them lazily in pages of the
+    /// given size.
+    ///
+    /// Any paging options set in `query_settings` are overridden. To ensure that pages do not
+    /// overlap, specify a sort order using `QuerySettings::order_by`. Each page is fetched with
+    /// failover, so consecutive pages may be served by different APICs.
+    pub fn stream_instances<'a>(
+        &'a self,
+        class_name: &'a str,
+        query_settings: QuerySettings,
+        page_size: NonZeroU64,
+    ) -> impl Stream<Item = Result<AciObject, ApicCommError>> + 'a {
+        paged_stream(page_size, move |page| {
+            self.get_instances_with_metadata(class_name, query_settings.clone().page(page_size, page))
+        })
+    }
+
+    round_robin_func! {
+        /// Returns the managed object with the given Distinguished Name (or some of its children or
+        /// descendants, depending on the query settings).
+        pub async fn get_objects(conn, dn: &str, query_settings: QuerySettings) -> Vec<AciObject> {
+            conn.get_objects(dn, query_settings.clone())
+        }
+    }
+
+    round_robin_func! {
+        /// Returns the managed object with the given Distinguished Name (or some of its children or
+        /// descendants, depending on the query settings) along with the metadata of the response.
+        pub async fn get_objects_with_metadata(conn, dn: &str, query_settings: QuerySettings) -> QueryResult {
+            conn.get_objects_with_metadata(dn, query_settings.clone())
+        }
+    }
+
+    round_robin_func! {
+        @fail_over should_fail_over_unsent;
+        /// Posts (creates or modifies) the supplied managed object in the fabric.
+        ///
+        /// As posting is not idempotent, the next APIC is only tried if no connection to the
+        /// current one could be established, not after timeouts or server errors.
+        pub async fn post_object(conn, obj: &AciObject) -> Vec<AciObject> {
+            conn.post_object(obj)
+        }
+    }
+
+    round_robin_func! {
+        /// Deletes the object with the given Distinguished Name from the fabric.
+        pub async fn delete_object(conn, dn: &str) -> () {
+            conn.delete_object(dn)
+        }
+    }
+
+    round_robin_func! {
+        /// Deletes the object with the given Distinguished Name from the fabric, retrying the
+        /// request according to the given retry policy or, if none is given, the connection's retry
+        /// policy.
+        pub async fn delete_object_with_retry_policy(conn, dn: &str, retry_policy: Option<RetryPolicy>) -> () {
+            conn.delete_object_with_retry_policy(dn, retry_policy.clone())
+        }
+    }
+}
+
+
+/// Assembles an `ApicMultiConnection` with non-default settings, which are applied to the
+/// connection to each APIC.
+///
+/// Obtained using `ApicMultiConnection::builder`.
+#[derive(Clone, Debug)]
+pub struct ApicMultiConnectionBuilder<A: ApicAuthenticator + Clone> {
+    apic_uris: Vec<Url>,
+    authenticator: A,
+    settings: ConnectionSettings,
+    circuit_breaker_policy: CircuitBreakerPolicy,
+}
+impl<A: ApicAuthenticator + Clone + 'static> ApicMultiConnectionBuilder<A> {
+    /// Sets the timeout applied to each request (including establishing the connection and
+    /// receiving the response) and returns the builder. Defaults to 30 seconds.
+    ///
+    /// Timeouts cause failover to the next APIC.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout applied to login, session refresh and logout requests and returns the
+    /// builder. Defaults to the request timeout.
+    pub fn login_timeout(mut self, login_timeout: Duration) -> Self {
+        self.settings.login_timeout = Some(login_timeout);
+        self
+    }
+
+    /// Sets the timeout applied to establishing TCP connections and returns the builder. By
+    /// default, only the request timeout applies.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.settings.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets the TLS options and returns the builder.
+    ///
+    /// Certificates can be pinned per APIC using `TlsOptions::pin_certificate`.
+    pub fn tls_options(mut self, tls_options: TlsOptions) -> Self {
+        self.settings.tls_options = tls_options;
+        self
+    }
+
+    /// Sets the proxy to connect through and returns the builder.
+    pub fn proxy(mut self, proxy: ProxyOptions) -> Self {
+        self.settings.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the transport used to send requests and returns the builder.
+    ///
+    /// A custom transport replaces the TLS options, the proxy, the connect timeout and the HTTP/2
+    /// preference, which are only applied to the transport created by the builder.
+    pub fn transport(mut self, transport: Arc<dyn HttpTransport>) -> Self {
+        self.settings.transport = Some(transport);
+        self
+    }
+
+    /// Sets the User-Agent header sent with each request and returns the builder.
+    pub fn user_agent(self, user_agent: &str) -> Self {
+        self.header(USER_AGENT.as_str(), user_agent)
+    }
+
+    /// Adds a header sent with each request and returns the builder.
+    ///
+    /// Headers set by the authenticator take precedence. Invalid header names or values are
+    /// reported by `build`.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.settings.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.settings.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Sets whether HTTP/2 is offered to the APICs during the TLS handshake and returns the
+    /// builder. If an APIC does not accept it, HTTP/1.1 is used.
+    pub fn prefer_http2(mut self, prefer_http2: bool) -> Self {
+        self.settings.prefer_http2 = prefer_http2;
+        self
+    }
+
+    /// Sets the retry policy applied to idempotent requests and returns the builder.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.settings.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the rate limiter shared by the connections to all APICs and returns the builder.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.settings.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sets the policy of the circuit breakers tracking the health of each APIC and returns the
+    /// builder.
+    pub fn circuit_breaker_policy(mut self, circuit_breaker_policy: CircuitBreakerPolicy) -> Self {
+        self.circuit_breaker_policy = circuit_breaker_policy;
+        self
+    }
+
+    /// Creates the connection, logging in to the first APIC that accepts the login.
+    pub async fn build(self) -> Result<ApicMultiConnection<A>, ApicCommError> {
+        let ApicMultiConnectionBuilder { apic_uris, authenticator, settings, circuit_breaker_policy } = self;
+        let transport = settings.build_transport()?;
+        let mut circuit_breakers = vec![CircuitBreaker::default(); apic_uris.len()];
+
         let mut err = ApicCommError::NoApicSpecified;
-        for i in 0..apic_uris.len() {
-            info!("initial attempt to use APIC {}", &apic_uris[i]);
-            let conn_res = ApicConnection::new(
-                apic_uris[i].clone(),
+        for (i, apic_uri) in apic_uris.iter().enumerate() {
+            info!("initial attempt to use APIC {}", apic_uri);
+            let conn_res = ApicConnection::connect(
+                apic_uri.clone(),
+                transport.clone(),
                 authenticator.clone(),
-                timeout,
+                &settings,
             ).await;
             match conn_res {
                 Err(e) => {
+                    if should_fail_over(&e) {
+                        circuit_breakers[i].record_failure(&circuit_breaker_policy);
+                    }
                     err = e;
                     // continue loop
                 },
@@ -159,9 +605,15 @@ impl<A: ApicAuthenticator + Clone> ApicMultiConnection<A> {
                         conn,
                     };
                     let amc = ApicMultiConnection {
+                        retry_policy: RwLock::new(settings.retry_policy.clone()),
+                        rate_limiter: RwLock::new(settings.rate_limiter.clone()),
+                        logout_on_drop: RwLock::new(false),
                         apic_uris,
+                        transport,
                         authenticator,
-                        timeout,
+                        settings,
+                        circuit_breaker_policy,
+                        circuit_breakers: Mutex::new(circuit_breakers),
                         cur_holder: RwLock::new(ach),
                     };
                     return Ok(amc);
@@ -172,33 +624,223 @@ impl<A: ApicAuthenticator + Clone> ApicMultiConnection<A> {
         // the error returned by the last APIC is returned to the caller
         Err(err)
     }
+}
 
-    round_robin_func! {
-        /// Return instances of the given class.
-        pub async fn get_instances(conn, class_name: &str, query_settings: QuerySettings) -> Vec<AciObject> {
-            conn.get_instances(class_name, query_settings.clone())
-        }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use hyper::{Response, StatusCode};
+    use json::JsonValue;
+
+    use crate::auth::ApicUsernamePasswordAuth;
+    use crate::conn::ApicError;
+    use crate::test_server::{self, EMPTY_RESPONSE};
+
+    #[test]
+    fn error_classification() {
+        let error_response = |status: StatusCode| {
+            let (mut parts, _) = Response::new(()).into_parts();
+            parts.status = status;
+            ApicCommError::ErrorResponse(JsonValue::Null, Box::new(parts))
+        };
+        let apic_error = |code: u32, text: &str, status: StatusCode| {
+            let (mut parts, _) = Response::new(()).into_parts();
+            parts.status = status;
+            ApicCommError::ApicError(ApicError::new(code, String::from(text), status, None), Box::new(parts))
+        };
+
+        assert!(ApicCommError::Timeout.is_transient());
+        assert!(!ApicCommError::Timeout.is_fatal());
+        assert!(error_response(StatusCode::SERVICE_UNAVAILABLE).is_transient());
+        assert!(error_response(StatusCode::SERVICE_UNAVAILABLE).is_server_error());
+        assert!(error_response(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(error_response(StatusCode::TOO_MANY_REQUESTS).is_client_error());
+
+        assert!(ApicCommError::InvalidCredentials.is_authentication_failure());
+        let token_timeout = apic_error(403, "Token timeout", StatusCode::FORBIDDEN);
+        assert!(token_timeout.is_session_rejection());
+        assert!(token_timeout.is_authentication_failure());
+        assert!(!token_timeout.is_fatal());
+        let no_privileges = apic_error(403, "user does not have the required privileges", StatusCode::FORBIDDEN);
+        assert!(!no_privileges.is_session_rejection());
+        assert!(no_privileges.is_fatal());
+        assert!(!error_response(StatusCode::FORBIDDEN).is_session_rejection());
+        let unauthorized = apic_error(401, "Need a valid webtoken cookie", StatusCode::UNAUTHORIZED);
+        assert!(unauthorized.is_session_rejection());
+
+        let not_found = error_response(StatusCode::NOT_FOUND);
+        assert!(not_found.is_client_error());
+        assert!(!not_found.is_server_error());
+        assert!(!not_found.is_transient());
+        assert!(not_found.is_fatal());
+        assert!(error_response(StatusCode::INTERNAL_SERVER_ERROR).is_server_error());
+        assert!(ApicCommError::MissingDistinguishedName.is_fatal());
     }
 
-    round_robin_func! {
-        /// Returns the managed object with the given Distinguished Name (or some of its children or
-        /// descendants, depending on the query settings).
-        pub async fn get_objects(conn, dn: &str, query_settings: QuerySettings) -> Vec<AciObject> {
-            conn.get_objects(dn, query_settings.clone())
-        }
+    #[tokio::test]
+    async fn login_again_after_idling() {
+        let (addr, logins) = test_server::serve_apic(|req| {
+            match req.uri().path() {
+                "/api/aaaRefresh.json" => {
+                    let body = r#"{"totalCount":"1","imdata":[{"error":{"attributes":{"code":"403","text":"Token was invalid (Error: Token timeout)"}}}]}"#;
+                    (StatusCode::FORBIDDEN, String::from(body))
+                },
+                _ => (StatusCode::OK, String::from(EMPTY_RESPONSE)),
+            }
+        });
+        let uris = vec![test_server::base_uri(addr)];
+        let auth = ApicUsernamePasswordAuth::new("admin".into(), "password".into());
+        let multi_conn = ApicMultiConnection::new(uris, auth, Duration::from_secs(5))
+            .await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+
+        // idle past the session's refresh timeout
+        test_server::skip_time(Duration::from_secs(601)).await;
+
+        assert_eq!(multi_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
     }
 
-    round_robin_func! {
-        /// Posts (creates or modifies) the supplied managed object in the fabric.
-        pub async fn post_object(conn, obj: &AciObject) -> Vec<AciObject> {
-            conn.post_object(obj)
-        }
+    #[tokio::test]
+    async fn logout_on_drop() {
+        let logouts = Arc::new(AtomicUsize::new(0));
+        let server_logouts = logouts.clone();
+        let (addr, _) = test_server::serve_apic(move |req| {
+            match req.uri().path() {
+                "/api/aaaLogout.json" => {
+                    server_logouts.fetch_add(1, Ordering::SeqCst);
+                    (StatusCode::OK, String::from(EMPTY_RESPONSE))
+                },
+                other => panic!("unexpected request for {}", other),
+            }
+        });
+        let uris = vec![test_server::base_uri(addr)];
+        let auth = ApicUsernamePasswordAuth::new("admin".into(), "password".into());
+
+        let multi_conn = ApicMultiConnection::new(uris, auth, Duration::from_secs(5))
+            .await.unwrap();
+        multi_conn.set_logout_on_drop(true).await;
+        drop(multi_conn);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while logouts.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(logouts.load(Ordering::SeqCst), 1);
     }
 
-    round_robin_func! {
-        /// Deletes the object with the given Distinguished Name from the fabric.
-        pub async fn delete_object(conn, dn: &str) -> () {
-            conn.delete_object(dn)
-        }
+    #[tokio::test]
+    async fn failover_on_transient_error() {
+        let (unavailable_addr, _) = test_server::serve_apic(|req| {
+            match req.uri().path() {
+                "/api/class/fvTenant.json" => (StatusCode::SERVICE_UNAVAILABLE, String::from("{}")),
+                _ => (StatusCode::NOT_FOUND, String::from("{}")),
+            }
+        });
+        let (available_addr, _) = test_server::serve_apic(|_req| (StatusCode::OK, String::from(EMPTY_RESPONSE)));
+        let uris = vec![
+            test_server::base_uri(unavailable_addr),
+            test_server::base_uri(available_addr),
+        ];
+        let auth = ApicUsernamePasswordAuth::new("admin".into(), "password".into());
+
+        // a transient error causes failover
+        let multi_conn = ApicMultiConnection::new(uris.clone(), auth.clone(), Duration::from_secs(5))
+            .await.unwrap();
+        assert_eq!(multi_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+
+        // a client error does not
+        let multi_conn = ApicMultiConnection::new(uris, auth, Duration::from_secs(5))
+            .await.unwrap();
+        let err = multi_conn.get_instances("fvBD", QuerySettings::new()).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn no_failover_after_posting() {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let (timeout_addr, _) = test_server::serve_apic(|_req| (StatusCode::GATEWAY_TIMEOUT, String::from("{}")));
+        let server_posts = posts.clone();
+        let (available_addr, _) = test_server::serve_apic(move |_req| {
+            server_posts.fetch_add(1, Ordering::SeqCst);
+            (StatusCode::OK, String::from(EMPTY_RESPONSE))
+        });
+        let uris = vec![
+            test_server::base_uri(timeout_addr),
+            test_server::base_uri(available_addr),
+        ];
+        let multi_conn = ApicMultiConnection::new(
+            uris.clone(),
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+            Duration::from_secs(5),
+        ).await.unwrap();
+
+        // the APIC might have processed the request, so it is not sent to the next one
+        let mut attribs = std::collections::HashMap::new();
+        attribs.insert(String::from("dn"), String::from("uni/tn-x"));
+        let tenant = AciObject::new(String::from("fvTenant"), attribs, Vec::new()).unwrap();
+        let err = multi_conn.post_object(&tenant).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::GATEWAY_TIMEOUT));
+        assert_eq!(posts.load(Ordering::SeqCst), 0);
+        assert_eq!(multi_conn.current_apic().await, uris[0]);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let broken_queries = Arc::new(AtomicUsize::new(0));
+        let server_broken_queries = broken_queries.clone();
+        let (broken_addr, _) = test_server::serve_apic(move |_req| {
+            server_broken_queries.fetch_add(1, Ordering::SeqCst);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::from("{}"))
+        });
+        let overloaded = Arc::new(AtomicBool::new(false));
+        let server_overloaded = overloaded.clone();
+        let (flaky_addr, _) = test_server::serve_apic(move |_req| {
+            if server_overloaded.load(Ordering::SeqCst) {
+                (StatusCode::SERVICE_UNAVAILABLE, String::from("{}"))
+            } else {
+                (StatusCode::OK, String::from(EMPTY_RESPONSE))
+            }
+        });
+        let refused_addr = {
+            // bind and immediately release a port so that connections to it are refused
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let broken_uri = test_server::base_uri(broken_addr);
+        let flaky_uri = test_server::base_uri(flaky_addr);
+        let refused_uri = test_server::base_uri(refused_addr);
+
+        let multi_conn = ApicMultiConnection::builder(
+            vec![broken_uri.clone(), refused_uri.clone(), flaky_uri.clone()],
+            ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+        )
+            .timeout(Duration::from_secs(5))
+            .circuit_breaker_policy(
+                CircuitBreakerPolicy::new()
+                    .failure_threshold(1)
+                    .open_duration(Duration::from_secs(60))
+            )
+            .build()
+            .await.unwrap();
+        assert_eq!(multi_conn.current_apic().await, broken_uri);
+
+        // the server error and the refused connection cause failover
+        assert_eq!(multi_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap().len(), 0);
+        assert_eq!(multi_conn.current_apic().await, flaky_uri);
+        assert_eq!(broken_queries.load(Ordering::SeqCst), 1);
+        assert!(multi_conn.is_apic_skipped(&broken_uri));
+        assert!(multi_conn.is_apic_skipped(&refused_uri));
+        assert!(!multi_conn.is_apic_skipped(&flaky_uri));
+
+        // the APICs known to be unavailable are not tried again
+        overloaded.store(true, Ordering::SeqCst);
+        let err = multi_conn.get_instances("fvTenant", QuerySettings::new()).await.unwrap_err();
+        assert_eq!(err.response_status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(broken_queries.load(Ordering::SeqCst), 1);
+        assert!(multi_conn.is_apic_skipped(&flaky_uri));
     }
 }
@@ -38,14 +38,14 @@ pub fn split_dn(dn: &str) -> Result<Vec<&str>, SplitDnError> {
     // string slices address bytes, so iterate string as bytes
     let bs: Vec<u8> = dn.bytes().collect();
     for i in 0..bs.len() {
-        if bs[i] == ('[' as u8) {
+        if bs[i] == b'[' {
             bracket_depth += 1;
-        } else if bs[i] == (']' as u8) {
+        } else if bs[i] == b']' {
             if bracket_depth == 0 {
                 return Err(SplitDnError::OverclosedSquareBracket(i));
             }
             bracket_depth -= 1;
-        } else if bs[i] == ('/' as u8) && bracket_depth == 0 {
+        } else if bs[i] == b'/' && bracket_depth == 0 {
             // this is the split point
             slices.push(&dn[start_index..i]);
             start_index = i + 1;
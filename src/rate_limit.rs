@@ -0,0 +1,353 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::StatusCode;
+use hyper::header::RETRY_AFTER;
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::conn::ApicCommError;
+
+
+/// The default delay before sending further requests after the APIC has signalled throttling.
+const DEFAULT_INITIAL_THROTTLE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The default maximum delay before sending further requests after the APIC has repeatedly
+/// signalled throttling.
+const DEFAULT_MAX_THROTTLE_BACKOFF: Duration = Duration::from_secs(60);
+
+
+/// Limits the rate and concurrency of requests to the Application Policy Infrastructure Controller
+/// (APIC).
+///
+/// Requests are admitted according to a token bucket: the bucket holds up to `burst` tokens and is
+/// refilled at `requests_per_second` tokens per second; each request consumes one token. Optionally,
+/// the number of requests in flight at the same time is capped as well.
+///
+/// If the APIC signals throttling (HTTP 429 or 503), no requests are admitted until a backoff delay
+/// has passed. The delay is taken from the `Retry-After` header if the APIC sends one; otherwise,
+/// it starts at the initial throttle backoff and doubles with each consecutive throttling response
+/// up to the maximum throttle backoff.
+///
+/// Clones of a RateLimiter share their state, so a single budget can be applied to multiple
+/// connections by passing clones of the same RateLimiter to each of them. The settings are fixed
+/// once the RateLimiter has been created; use `RateLimiter::builder` to change the defaults.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    inner: Arc<RateLimiterInner>,
+}
+
+#[derive(Clone, Debug)]
+struct RateLimitSettings {
+    requests_per_second: f64,
+    burst: u32,
+    max_concurrent: Option<usize>,
+    initial_throttle_backoff: Duration,
+    max_throttle_backoff: Duration,
+}
+
+#[derive(Debug)]
+struct RateLimiterInner {
+    settings: RateLimitSettings,
+    concurrency: Option<Arc<Semaphore>>,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+    throttled_until: Option<Instant>,
+    next_throttle_backoff: Duration,
+}
+
+/// Permission to perform a request, obtained from `RateLimiter::acquire`.
+///
+/// The concurrency slot taken by the request is released when the permit is dropped.
+#[derive(Debug)]
+pub struct RateLimitPermit {
+    _concurrency: Option<OwnedSemaphorePermit>,
+}
+
+impl RateLimiter {
+    /// Creates a new RateLimiter admitting the given number of requests per second on average and
+    /// up to `burst` requests at once.
+    ///
+    /// This is a shorthand for `RateLimiter::builder(requests_per_second, burst).build()`.
+    ///
+    /// Panics if `requests_per_second` is not positive and finite.
+    pub fn new(requests_per_second: f64, burst: u32) -> RateLimiter {
+        Self::builder(requests_per_second, burst)
+            .build()
+    }
+
+    /// Returns a builder for a RateLimiter admitting the given number of requests per second on
+    /// average and up to `burst` requests at once, with otherwise non-default settings.
+    ///
+    /// Panics if `requests_per_second` is not positive and finite.
+    pub fn builder(requests_per_second: f64, burst: u32) -> RateLimiterBuilder {
+        assert!(
+            requests_per_second.is_finite() && requests_per_second > 0.0,
+            "requests per second must be positive",
+        );
+        RateLimiterBuilder {
+            settings: RateLimitSettings {
+                requests_per_second,
+                burst: burst.max(1),
+                max_concurrent: None,
+                initial_throttle_backoff: DEFAULT_INITIAL_THROTTLE_BACKOFF,
+                max_throttle_backoff: DEFAULT_MAX_THROTTLE_BACKOFF,
+            },
+        }
+    }
+
+    /// Returns the average number of requests admitted per second.
+    pub fn requests_per_second(&self) -> f64 {
+        self.inner.settings.requests_per_second
+    }
+
+    /// Returns the maximum number of requests admitted at once.
+    pub fn burst(&self) -> u32 {
+        self.inner.settings.burst
+    }
+
+    /// Returns the maximum number of requests in flight at the same time, if capped.
+    pub fn max_concurrent(&self) -> Option<usize> {
+        self.inner.settings.max_concurrent
+    }
+
+    /// Creates a new RateLimiter with the given settings and a full bucket.
+    fn with_settings(settings: RateLimitSettings) -> RateLimiter {
+        let concurrency = settings.max_concurrent
+            .map(|mc| Arc::new(Semaphore::new(mc)));
+        let state = BucketState {
+            tokens: f64::from(settings.burst),
+            last_refill: Instant::now(),
+            throttled_until: None,
+            next_throttle_backoff: settings.initial_throttle_backoff,
+        };
+        RateLimiter {
+            inner: Arc::new(RateLimiterInner {
+                settings,
+                concurrency,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// Waits until a request may be performed and returns the permit to perform it.
+    pub async fn acquire(&self) -> RateLimitPermit {
+        let concurrency = match &self.inner.concurrency {
+            Some(semaphore) => Some(
+                semaphore.clone()
+                    .acquire_owned()
+                    .await
+                    .expect("rate limiter semaphore is never closed")
+            ),
+            None => None,
+        };
+
+        loop {
+            let wait = {
+                let mut state = self.inner.state.lock()
+                    .expect("rate limiter state is poisoned");
+                let now = Instant::now();
+                match state.throttled_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        state.throttled_until = None;
+                        let elapsed = now.saturating_duration_since(state.last_refill);
+                        let settings = &self.inner.settings;
+                        state.tokens = (state.tokens + elapsed.as_secs_f64() * settings.requests_per_second)
+                            .min(f64::from(settings.burst));
+                        state.last_refill = now;
+                        if state.tokens >= 1.0 {
+                            state.tokens -= 1.0;
+                            break;
+                        }
+                        Duration::from_secs_f64((1.0 - state.tokens) / self.inner.settings.requests_per_second)
+                    },
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+
+        RateLimitPermit {
+            _concurrency: concurrency,
+        }
+    }
+
+    /// Updates the throttling state according to the outcome of a request.
+    pub(crate) fn report<T>(&self, result: &Result<T, ApicCommError>) {
+        let mut state = self.inner.state.lock()
+            .expect("rate limiter state is poisoned");
+        match result {
+            Err(e) if is_throttling(e) => {
+                let backoff = retry_after(e)
+                    .unwrap_or(state.next_throttle_backoff);
+                warn!("APIC is throttling requests ({}); pausing for {:?}", e, backoff);
+                let until = Instant::now() + backoff;
+                if state.throttled_until.map(|t| t < until).unwrap_or(true) {
+                    state.throttled_until = Some(until);
+                }
+                state.next_throttle_backoff = (state.next_throttle_backoff * 2)
+                    .min(self.inner.settings.max_throttle_backoff);
+            },
+            _ => {
+                state.next_throttle_backoff = self.inner.settings.initial_throttle_backoff;
+            },
+        }
+    }
+}
+
+
+/// A builder for a RateLimiter with non-default settings.
+///
+/// Obtained using `RateLimiter::builder`.
+#[derive(Clone, Debug)]
+pub struct RateLimiterBuilder {
+    settings: RateLimitSettings,
+}
+impl RateLimiterBuilder {
+    /// Caps the number of requests in flight at the same time and returns the builder.
+    pub fn max_concurrent_requests(mut self, max_concurrent: usize) -> Self {
+        self.settings.max_concurrent = Some(max_concurrent.max(1));
+        self
+    }
+
+    /// Sets the initial and maximum delay applied when the APIC signals throttling and returns the
+    /// builder.
+    pub fn throttle_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.settings.initial_throttle_backoff = initial;
+        self.settings.max_throttle_backoff = max.max(initial);
+        self
+    }
+
+    /// Creates the RateLimiter, starting with a full bucket.
+    pub fn build(self) -> RateLimiter {
+        RateLimiter::with_settings(self.settings)
+    }
+}
+
+
+/// Returns whether the given error signals that the APIC is throttling requests.
+fn is_throttling(error: &ApicCommError) -> bool {
+    matches!(
+        error.response_status(),
+        Some(StatusCode::TOO_MANY_REQUESTS) | Some(StatusCode::SERVICE_UNAVAILABLE)
+    )
+}
+
+/// Returns the delay requested by the `Retry-After` header of the APIC response represented by the
+/// given error, if any.
+fn retry_after(error: &ApicCommError) -> Option<Duration> {
+    let parts = match error {
+        ApicCommError::ErrorResponse(_, parts) => parts,
+        ApicCommError::ApicError(_, parts) => parts,
+        _ => return None,
+    };
+    parts.headers.get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::ApicError;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn token_bucket() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(20.0, 2);
+
+        // the burst is admitted immediately, further requests at the given rate
+        let start = Instant::now();
+        for _ in 0..2 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(0));
+        for _ in 0..2 {
+            limiter.acquire().await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(110), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn concurrency() {
+        tokio::time::pause();
+        let limiter = RateLimiter::builder(1000.0, 1000)
+            .max_concurrent_requests(1)
+            .build();
+        let permit = limiter.acquire().await;
+
+        let other_limiter = limiter.clone();
+        let acquired = Arc::new(AtomicBool::new(false));
+        let task_acquired = acquired.clone();
+        let waiting = tokio::spawn(async move {
+            other_limiter.acquire().await;
+            task_acquired.store(true, Ordering::SeqCst);
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!acquired.load(Ordering::SeqCst));
+
+        drop(permit);
+        waiting.await.unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn throttling() {
+        tokio::time::pause();
+        let limiter = RateLimiter::builder(1000.0, 1000)
+            .throttle_backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .build();
+        let throttled: Result<(), ApicCommError> = {
+            let (mut parts, _) = hyper::Response::new(()).into_parts();
+            parts.status = StatusCode::SERVICE_UNAVAILABLE;
+            Err(ApicCommError::ErrorResponse(json::JsonValue::Null, Box::new(parts)))
+        };
+        limiter.report(&throttled);
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // a second throttling response doubles the delay
+        limiter.report(&throttled);
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // success resets it
+        limiter.report(&Ok(()));
+        limiter.report(&throttled);
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+    #[test]
+    fn retry_after_header() {
+        let response_parts = || {
+            let (mut parts, _) = hyper::Response::new(()).into_parts();
+            parts.status = StatusCode::TOO_MANY_REQUESTS;
+            parts.headers.insert(RETRY_AFTER, hyper::header::HeaderValue::from_static("2"));
+            Box::new(parts)
+        };
+
+        let error_response = ApicCommError::ErrorResponse(json::JsonValue::Null, response_parts());
+        assert_eq!(retry_after(&error_response), Some(Duration::from_secs(2)));
+
+        let apic_error = ApicCommError::ApicError(
+            ApicError::new(429, String::from("Too many requests"), StatusCode::TOO_MANY_REQUESTS, None),
+            response_parts(),
+        );
+        assert_eq!(retry_after(&apic_error), Some(Duration::from_secs(2)));
+    }
+}
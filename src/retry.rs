@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+use crate::conn::ApicCommError;
+
+
+/// Governs whether and how requests to the Application Policy Infrastructure Controller (APIC)
+/// are attempted again after failing with a transient error.
+///
+/// Retries are only performed for idempotent requests (queries and deletions). The delay before
+/// each retry grows exponentially, starting at the initial backoff and being multiplied by the
+/// backoff factor after each attempt, up to the maximum backoff. With jitter enabled, a random
+/// delay between half and all of the calculated delay is chosen, so that clients failing at the
+/// same time do not retry in lockstep.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff_factor: u32,
+    jitter: bool,
+    retry_on_timeout: bool,
+    retry_on_connection_error: bool,
+    retryable_statuses: HashSet<StatusCode>,
+}
+impl RetryPolicy {
+    /// Creates a new retry policy with common defaults: three attempts, backoff starting at 250ms
+    /// and doubling up to 10s with jitter, retrying after timeouts, connection errors and the
+    /// statuses 502 (Bad Gateway), 503 (Service Unavailable) and 504 (Gateway Timeout).
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            backoff_factor: 2,
+            jitter: true,
+            retry_on_timeout: true,
+            retry_on_connection_error: true,
+            retryable_statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ].into_iter().collect(),
+        }
+    }
+
+    /// Creates a new retry policy which makes a single attempt.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy::new()
+            .max_attempts(1)
+    }
+
+    /// Sets the maximum number of attempts (including the first one) and returns the RetryPolicy
+    /// object. Values below 1 are treated as 1.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry and returns the RetryPolicy object.
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets the maximum delay between attempts and returns the RetryPolicy object.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Sets the factor by which the delay grows after each attempt and returns the RetryPolicy
+    /// object.
+    pub fn backoff_factor(mut self, backoff_factor: u32) -> Self {
+        self.backoff_factor = backoff_factor;
+        self
+    }
+
+    /// Sets whether the delay between attempts is randomized and returns the RetryPolicy object.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets whether requests that have timed out are retried and returns the RetryPolicy object.
+    pub fn retry_on_timeout(mut self, retry: bool) -> Self {
+        self.retry_on_timeout = retry;
+        self
+    }
+
+    /// Sets whether requests that have failed due to a connection error (e.g. the connection having
+    /// been refused or reset) are retried and returns the RetryPolicy object.
+    pub fn retry_on_connection_error(mut self, retry: bool) -> Self {
+        self.retry_on_connection_error = retry;
+        self
+    }
+
+    /// Sets the HTTP statuses of responses after which requests are retried and returns the
+    /// RetryPolicy object.
+    pub fn retryable_statuses(mut self, statuses: &[StatusCode]) -> Self {
+        self.retryable_statuses = statuses.iter().copied().collect();
+        self
+    }
+
+    /// Returns the maximum number of attempts (including the first one).
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns whether a request that has failed with the given error on the given attempt
+    /// (starting at 1) should be attempted again.
+    pub fn should_retry(&self, error: &ApicCommError, attempt: u32) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        if let ApicCommError::Timeout = error {
+            self.retry_on_timeout
+        } else if error.is_connection_error() {
+            self.retry_on_connection_error
+        } else {
+            error.response_status()
+                .map(|s| self.retryable_statuses.contains(&s))
+                .unwrap_or(false)
+        }
+    }
+
+    /// Returns the delay before the attempt following the given attempt (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial_backoff;
+        for _ in 1..attempt {
+            delay = delay.checked_mul(self.backoff_factor)
+                .unwrap_or(self.max_backoff);
+            if delay >= self.max_backoff {
+                break;
+            }
+        }
+        delay = delay.min(self.max_backoff);
+
+        if self.jitter {
+            // choose uniformly between half and all of the delay
+            let half = delay / 2;
+            let spread_nanos = (delay - half).as_nanos() as u64;
+            if spread_nanos > 0 {
+                return half + Duration::from_nanos(random_u64() % (spread_nanos + 1));
+            }
+        }
+        delay
+    }
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a random number that is good enough for jitter.
+fn random_u64() -> u64 {
+    // each RandomState is seeded with fresh random keys
+    RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::new()
+            .max_attempts(10)
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(1000))
+            .jitter(false);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(100), Duration::from_millis(1000));
+
+        let jittered = policy.jitter(true);
+        for attempt in 1..6 {
+            let unjittered = jittered.clone().jitter(false).backoff(attempt);
+            let delay = jittered.backoff(attempt);
+            assert!(delay >= unjittered / 2 && delay <= unjittered, "{:?} vs {:?}", delay, unjittered);
+        }
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = RetryPolicy::new();
+        assert!(policy.should_retry(&ApicCommError::Timeout, 1));
+        assert!(policy.should_retry(&ApicCommError::Timeout, 2));
+        assert!(!policy.should_retry(&ApicCommError::Timeout, 3));
+        assert!(!policy.should_retry(&ApicCommError::InvalidCredentials, 1));
+        assert!(!policy.clone().retry_on_timeout(false).should_retry(&ApicCommError::Timeout, 1));
+        assert!(!RetryPolicy::never().should_retry(&ApicCommError::Timeout, 1));
+    }
+}
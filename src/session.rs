@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use json::{self, JsonValue};
+use url::Url;
+
+use crate::auth::ApicAuthenticatorData;
+
+
+/// A snapshot of a login session with an Application Policy Infrastructure Controller (APIC),
+/// suitable for resuming the session in a different process.
+///
+/// The snapshot contains the session token; store it accordingly.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ApicSession {
+    base_uri: Url,
+    auth_data: ApicAuthenticatorData,
+    login_time: SystemTime,
+}
+impl ApicSession {
+    /// Creates a new session snapshot.
+    pub fn new(
+        base_uri: Url,
+        auth_data: ApicAuthenticatorData,
+        login_time: SystemTime,
+    ) -> ApicSession {
+        ApicSession {
+            base_uri,
+            auth_data,
+            login_time,
+        }
+    }
+
+    /// Returns the base URI of the APIC with which the session has been established.
+    pub fn base_uri(&self) -> &Url {
+        &self.base_uri
+    }
+
+    /// Returns the authentication data of the session.
+    pub fn auth_data(&self) -> &ApicAuthenticatorData {
+        &self.auth_data
+    }
+
+    /// Returns the wall-clock time at which the session was last logged in or refreshed.
+    pub fn login_time(&self) -> SystemTime {
+        self.login_time
+    }
+
+    /// Returns whether the session has timed out at the given time.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        match now.duration_since(self.login_time) {
+            Ok(elapsed) => elapsed >= self.auth_data.refresh_timeout(),
+            // login time is in the future; the clock has probably been adjusted
+            Err(_) => true,
+        }
+    }
+
+    /// Serializes the session into a JSON value.
+    pub fn to_json(&self) -> JsonValue {
+        let login_time_secs = self.login_time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let challenge = match self.auth_data.apic_challenge() {
+            Some(c) => JsonValue::from(c),
+            None => JsonValue::Null,
+        };
+        json::object! {
+            baseUri: self.base_uri.as_str(),
+            cookie: self.auth_data.apic_cookie(),
+            challenge: challenge,
+            refreshTimeoutSeconds: self.auth_data.refresh_timeout().as_secs(),
+            loginTime: login_time_secs,
+        }
+    }
+
+    /// Deserializes a session from a JSON value produced by `to_json`.
+    pub fn from_json(value: &JsonValue) -> Result<ApicSession, SessionCacheError> {
+        let base_uri = value["baseUri"].as_str()
+            .and_then(|s| Url::parse(s).ok())
+            .ok_or(SessionCacheError::InvalidFormat)?;
+        let cookie = value["cookie"].as_str()
+            .ok_or(SessionCacheError::InvalidFormat)?;
+        let challenge = if value["challenge"].is_null() {
+            None
+        } else {
+            let c = value["challenge"].as_str()
+                .ok_or(SessionCacheError::InvalidFormat)?;
+            Some(String::from(c))
+        };
+        let refresh_timeout_secs = value["refreshTimeoutSeconds"].as_u64()
+            .ok_or(SessionCacheError::InvalidFormat)?;
+        let login_time_secs = value["loginTime"].as_u64()
+            .ok_or(SessionCacheError::InvalidFormat)?;
+
+        Ok(ApicSession::new(
+            base_uri,
+            ApicAuthenticatorData::new(
+                String::from(cookie),
+                challenge,
+                Duration::from_secs(refresh_timeout_secs),
+            ),
+            UNIX_EPOCH + Duration::from_secs(login_time_secs),
+        ))
+    }
+
+    /// Loads a session from the given file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ApicSession, SessionCacheError> {
+        let contents = fs::read_to_string(path)
+            .map_err(SessionCacheError::Io)?;
+        let value = json::parse(&contents)
+            .map_err(SessionCacheError::Parse)?;
+        ApicSession::from_json(&value)
+    }
+
+    /// Stores the session in the given file.
+    ///
+    /// On Unix systems, the file is made readable and writable only by its owner.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SessionCacheError> {
+        let path = path.as_ref();
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .map_err(SessionCacheError::Io)?;
+
+        // the mode is only applied to newly created files
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))
+                .map_err(SessionCacheError::Io)?;
+        }
+
+        file.write_all(self.to_json().dump().as_bytes())
+            .map_err(SessionCacheError::Io)?;
+        Ok(())
+    }
+}
+
+
+/// An error that occurred while loading or storing a session.
+#[derive(Debug)]
+pub enum SessionCacheError {
+    /// The session file could not be read or written.
+    Io(io::Error),
+
+    /// The session file does not contain valid JSON.
+    Parse(json::Error),
+
+    /// The session file does not have the expected structure.
+    InvalidFormat,
+}
+impl fmt::Display for SessionCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            SessionCacheError::Io(e)
+                => write!(f, "failed to access session file: {}", e),
+            SessionCacheError::Parse(e)
+                => write!(f, "failed to parse session file: {}", e),
+            SessionCacheError::InvalidFormat
+                => write!(f, "session file has an invalid format"),
+        }
+    }
+}
+impl Error for SessionCacheError {
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample_session() -> ApicSession {
+        ApicSession::new(
+            Url::parse("https://apic.example.com/").unwrap(),
+            ApicAuthenticatorData::new(
+                String::from("TOKEN"),
+                Some(String::from("CHALLENGE")),
+                Duration::from_secs(600),
+            ),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        )
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let session = sample_session();
+        let parsed = ApicSession::from_json(&session.to_json()).unwrap();
+        assert_eq!(parsed, session);
+
+        let no_challenge = ApicSession::new(
+            session.base_uri().clone(),
+            ApicAuthenticatorData::new(String::from("TOKEN"), None, Duration::from_secs(600)),
+            session.login_time(),
+        );
+        let parsed = ApicSession::from_json(&no_challenge.to_json()).unwrap();
+        assert_eq!(parsed, no_challenge);
+
+        assert!(matches!(
+            ApicSession::from_json(&json::object! { cookie: "TOKEN" }),
+            Err(SessionCacheError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn expiry() {
+        let session = sample_session();
+        let login_time = session.login_time();
+        assert!(!session.is_expired_at(login_time + Duration::from_secs(599)));
+        assert!(session.is_expired_at(login_time + Duration::from_secs(600)));
+        assert!(session.is_expired_at(login_time - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("aci-session-test-{}.json", std::process::id()));
+        let session = sample_session();
+        session.save(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = ApicSession::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, session);
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+use futures_util::sink::SinkExt;
+use futures_util::stream::{self, Stream, StreamExt};
+use json::JsonValue;
+use log::{debug, warn};
+use tokio::time::{Instant, Interval};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use url::Url;
+
+use crate::AciObject;
+use crate::auth::ApicAuthenticator;
+use crate::conn::{ApicCommError, ApicConnection, json_to_aci_objects, QueryResult, QuerySettings};
+use crate::tls::{TlsError, TlsStream};
+
+
+/// The default interval at which subscriptions are refreshed. The APIC expires subscriptions that
+/// have not been refreshed for 90 seconds.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(45);
+
+
+/// A notification about a change to a subscribed managed object.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubscriptionEvent {
+    subscription_ids: Vec<String>,
+    object: AciObject,
+}
+impl SubscriptionEvent {
+    /// Creates a new SubscriptionEvent for the given subscription IDs and changed object.
+    pub fn new(
+        subscription_ids: Vec<String>,
+        object: AciObject,
+    ) -> SubscriptionEvent {
+        SubscriptionEvent {
+            subscription_ids,
+            object,
+        }
+    }
+
+    /// Returns the IDs of the subscriptions which this notification concerns.
+    pub fn subscription_ids(&self) -> &Vec<String> {
+        &self.subscription_ids
+    }
+
+    /// Returns a reference to the changed object.
+    ///
+    /// Depending on the type of change, the object might only contain the changed attributes.
+    pub fn object(&self) -> &AciObject {
+        &self.object
+    }
+
+    /// Consumes this SubscriptionEvent and returns the changed object.
+    pub fn into_object(self) -> AciObject {
+        self.object
+    }
+
+    /// Returns the type of change (the `status` attribute of the object, e.g. `created`,
+    /// `modified` or `deleted`), if it is set.
+    pub fn status(&self) -> Option<&str> {
+        self.object.attributes().get("status")
+            .map(|s| s.as_str())
+    }
+}
+
+
+/// Parses a notification message pushed by the APIC through the WebSocket into subscription
+/// events, one per changed object.
+pub fn parse_notification(message: &str) -> Result<Vec<SubscriptionEvent>, ApicCommError> {
+    let body = json::parse(message)
+        .map_err(|e| ApicCommError::InvalidJson(e, String::from(message)))?;
+
+    let subscription_ids: Vec<String> = match &body["subscriptionId"] {
+        JsonValue::Array(ids) => ids.iter()
+            .filter_map(|id| id.as_str())
+            .map(String::from)
+            .collect(),
+        other => other.as_str()
+            .map(|id| vec![String::from(id)])
+            .unwrap_or_default(),
+    };
+
+    let objects = json_to_aci_objects(body.clone())
+        .map_err(|aoe| ApicCommError::InvalidAciObject(aoe, body))?;
+    Ok(objects.into_iter()
+        .map(|obj| SubscriptionEvent::new(subscription_ids.clone(), obj))
+        .collect())
+}
+
+
+/// A set of subscriptions to changes of managed objects, along with the WebSocket through which the
+/// APIC delivers notifications about them.
+pub struct ApicSubscription<'a, A: ApicAuthenticator> {
+    conn: &'a ApicConnection<A>,
+    socket: WebSocketStream<TlsStream>,
+    subscription_ids: Vec<String>,
+    refresh_interval: Duration,
+}
+impl<'a, A: ApicAuthenticator> ApicSubscription<'a, A> {
+    /// Opens the notification WebSocket for the session of the given connection.
+    pub async fn connect(conn: &'a ApicConnection<A>) -> Result<ApicSubscription<'a, A>, ApicCommError> {
+        conn.ensure_session().await?;
+        let websocket_uri = conn.websocket_uri().await;
+        Self::connect_to(conn, websocket_uri).await
+    }
+
+    /// Opens the notification WebSocket at the given URI for the session of the given connection.
+    ///
+    /// Unless the WebSocket is provided at a nonstandard location, you probably want to use
+    /// `ApicSubscription::connect`.
+    pub async fn connect_to(
+        conn: &'a ApicConnection<A>,
+        websocket_uri: Url,
+    ) -> Result<ApicSubscription<'a, A>, ApicCommError> {
+        debug!("opening WebSocket {}", websocket_uri);
+        let connecting = async {
+            let stream = conn.connect_websocket_stream(&websocket_uri)
+                .await
+                .map_err(|e| match e.downcast::<TlsError>() {
+                    Ok(tls_error) => ApicCommError::Tls(*tls_error),
+                    Err(other) => ApicCommError::WebSocket(Box::new(WsError::Io(io::Error::other(other)))),
+                })?;
+            tokio_tungstenite::client_async(websocket_uri.as_str(), stream)
+                .await
+                .map_err(|e| ApicCommError::WebSocket(Box::new(e)))
+        };
+        let (socket, _response) = match tokio::time::timeout(conn.timeout(), connecting).await {
+            Ok(sr) => sr?,
+            Err(_timeout) => return Err(ApicCommError::Timeout),
+        };
+
+        Ok(ApicSubscription {
+            conn,
+            socket,
+            subscription_ids: Vec::new(),
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+        })
+    }
+
+    /// Returns the IDs of the subscriptions that have been created.
+    pub fn subscription_ids(&self) -> &Vec<String> {
+        &self.subscription_ids
+    }
+
+    /// Returns the interval at which subscriptions are refreshed.
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    /// Sets the interval at which subscriptions are refreshed.
+    pub fn set_refresh_interval(&mut self, refresh_interval: Duration) {
+        self.refresh_interval = refresh_interval;
+    }
+
+    /// Subscribes to changes to the instances of the given class and returns their current state.
+    pub async fn subscribe_instances(
+        &mut self,
+        class_name: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        let result = self.conn.subscribe_instances(class_name, query_settings).await?;
+        self.add_subscription(&result)?;
+        Ok(result)
+    }
+
+    /// Subscribes to changes to the managed object with the given Distinguished Name (or some of
+    /// its children or descendants, depending on the query settings) and returns their current
+    /// state.
+    pub async fn subscribe_objects(
+        &mut self,
+        dn: &str,
+        query_settings: QuerySettings,
+    ) -> Result<QueryResult, ApicCommError> {
+        let result = self.conn.subscribe_objects(dn, query_settings).await?;
+        self.add_subscription(&result)?;
+        Ok(result)
+    }
+
+    fn add_subscription(&mut self, result: &QueryResult) -> Result<(), ApicCommError> {
+        let subscription_id = result.subscription_id()
+            .ok_or(ApicCommError::MissingSubscriptionId)?;
+        debug!("subscribed with ID {}", subscription_id);
+        self.subscription_ids.push(String::from(subscription_id));
+        Ok(())
+    }
+
+    /// Converts this subscription into a stream of change notifications.
+    ///
+    /// The subscriptions are refreshed in the background while the stream is being polled. The
+    /// stream ends when the APIC closes the WebSocket or a WebSocket error occurs.
+    pub fn into_stream(self) -> impl Stream<Item = Result<SubscriptionEvent, ApicCommError>> + 'a {
+        let refresh_timer = tokio::time::interval_at(
+            Instant::now() + self.refresh_interval,
+            self.refresh_interval,
+        );
+        let state = SubscriptionStreamState {
+            conn: self.conn,
+            socket: self.socket,
+            subscription_ids: self.subscription_ids,
+            refresh_timer,
+            pending: VecDeque::new(),
+            done: false,
+        };
+        stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(item) = state.pending.pop_front() {
+                    return Some((item, state));
+                }
+                if state.done {
+                    return None;
+                }
+                state.process_next().await;
+            }
+        })
+    }
+}
+
+
+/// The state of a stream of change notifications.
+struct SubscriptionStreamState<'a, A: ApicAuthenticator> {
+    conn: &'a ApicConnection<A>,
+    socket: WebSocketStream<TlsStream>,
+    subscription_ids: Vec<String>,
+    refresh_timer: Interval,
+    pending: VecDeque<Result<SubscriptionEvent, ApicCommError>>,
+    done: bool,
+}
+impl<'a, A: ApicAuthenticator> SubscriptionStreamState<'a, A> {
+    /// Waits for the next message on the WebSocket or the next subscription refresh, whichever
+    /// comes first, and handles it.
+    async fn process_next(&mut self) {
+        tokio::select! {
+            message = self.socket.next() => {
+                match message {
+                    None => {
+                        self.done = true;
+                    },
+                    Some(Ok(Message::Text(text))) => {
+                        match parse_notification(&text) {
+                            Ok(events) => self.pending.extend(events.into_iter().map(Ok)),
+                            Err(e) => self.pending.push_back(Err(e)),
+                        }
+                    },
+                    Some(Ok(Message::Ping(payload))) => {
+                        if let Err(e) = self.socket.send(Message::Pong(payload)).await {
+                            self.pending.push_back(Err(ApicCommError::WebSocket(Box::new(e))));
+                            self.done = true;
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) => {
+                        debug!("APIC closed the notification WebSocket");
+                        self.done = true;
+                    },
+                    Some(Ok(_)) => {
+                        // binary and pong messages are not used by the APIC
+                    },
+                    Some(Err(e)) => {
+                        self.pending.push_back(Err(ApicCommError::WebSocket(Box::new(e))));
+                        self.done = true;
+                    },
+                }
+            },
+            _ = self.refresh_timer.tick() => {
+                for subscription_id in &self.subscription_ids {
+                    debug!("refreshing subscription {}", subscription_id);
+                    if let Err(e) = self.conn.refresh_subscription(subscription_id).await {
+                        warn!("failed to refresh subscription {}: {}", subscription_id, e);
+                        self.pending.push_back(Err(e));
+                    }
+                }
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::StatusCode;
+    use tokio::net::TcpListener;
+    use tokio::sync::Notify;
+
+    use crate::auth::ApicUsernamePasswordAuth;
+    use crate::test_server::{
+        self, EMPTY_RESPONSE, LOGIN_RESPONSE, TEST_CERTIFICATE_FINGERPRINT, TEST_CERTIFICATE_PEM,
+        TEST_PRIVATE_KEY_PEM,
+    };
+    use crate::tls::{CertificateFingerprint, TlsOptions};
+
+    const NOTIFICATION: &str = r#"{
+        "subscriptionId": ["72057594037927937"],
+        "imdata": [
+            {"fvTenant": {"attributes": {"dn": "uni/tn-x", "status": "created"}}},
+            {"fvTenant": {"attributes": {"dn": "uni/tn-y", "status": "deleted"}}}
+        ]
+    }"#;
+
+    #[test]
+    fn parse_notification_events() {
+        let events = parse_notification(NOTIFICATION).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].subscription_ids(), &vec![String::from("72057594037927937")]);
+        assert_eq!(events[0].object().dn(), Some("uni/tn-x"));
+        assert_eq!(events[0].status(), Some("created"));
+        assert_eq!(events[1].object().rn(), Some("tn-y"));
+        assert_eq!(events[1].status(), Some("deleted"));
+    }
+
+    #[tokio::test]
+    async fn subscription_stream() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let refreshed = Arc::new(Notify::new());
+        let server_refreshes = refreshes.clone();
+        let server_refreshed = refreshed.clone();
+        let (http_addr, _) = test_server::serve_apic(move |req| {
+            let body = match req.uri().path() {
+                "/api/class/fvTenant.json" => {
+                    assert!(req.uri().query().unwrap().contains("subscription=yes"));
+                    r#"{"totalCount":"0","subscriptionId":"72057594037927937","imdata":[]}"#
+                },
+                "/api/subscriptionRefresh.json" => {
+                    assert_eq!(req.uri().query(), Some("id=72057594037927937"));
+                    server_refreshes.fetch_add(1, Ordering::SeqCst);
+                    server_refreshed.notify_one();
+                    EMPTY_RESPONSE
+                },
+                other => panic!("unexpected request for {}", other),
+            };
+            (StatusCode::OK, String::from(body))
+        });
+
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_addr = ws_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = ws_listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            // wait for the client to refresh its subscription
+            refreshed.notified().await;
+            ws.send(Message::Text(String::from(NOTIFICATION))).await.unwrap();
+            ws.close(None).await.unwrap();
+        });
+
+        let conn = test_server::connect(http_addr).await;
+        assert_eq!(conn.websocket_uri().await.as_str(), format!("ws://{}/socketTOKEN", http_addr));
+
+        let ws_uri = Url::parse(&format!("ws://{}/socketTOKEN", ws_addr)).unwrap();
+        let mut subscription = ApicSubscription::connect_to(&conn, ws_uri).await.unwrap();
+        subscription.set_refresh_interval(Duration::from_millis(100));
+        let initial = subscription.subscribe_instances("fvTenant", QuerySettings::new()).await.unwrap();
+        assert_eq!(initial.objects().len(), 0);
+        assert_eq!(subscription.subscription_ids(), &vec![String::from("72057594037927937")]);
+
+        let events: Vec<SubscriptionEvent> = subscription.into_stream()
+            .map(|e| e.unwrap())
+            .collect()
+            .await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].object().dn(), Some("uni/tn-x"));
+        assert_eq!(events[1].object().dn(), Some("uni/tn-y"));
+        assert!(refreshes.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[tokio::test]
+    async fn secure_websocket_applies_tls_options() {
+        let http_addr = test_server::serve(|_req| (StatusCode::OK, String::from(LOGIN_RESPONSE)));
+
+        let identity = native_tls::Identity::from_pkcs8(
+            TEST_CERTIFICATE_PEM.as_bytes(),
+            TEST_PRIVATE_KEY_PEM.as_bytes(),
+        ).unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::new(identity).unwrap()
+        );
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_addr = ws_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = ws_listener.accept().await.unwrap();
+                // clients refusing the certificate abort the handshake
+                if let Ok(tls) = acceptor.accept(tcp).await {
+                    let mut ws = tokio_tungstenite::accept_async(tls).await.unwrap();
+                    ws.close(None).await.unwrap();
+                }
+            }
+        });
+        let ws_uri = Url::parse(&format!("wss://localhost:{}/socketTOKEN", ws_addr.port())).unwrap();
+
+        let connect = |tls_options: TlsOptions| async move {
+            ApicConnection::builder(
+                Url::parse(&format!("http://{}/", http_addr)).unwrap(),
+                ApicUsernamePasswordAuth::new("admin".into(), "password".into()),
+            )
+                .timeout(Duration::from_secs(5))
+                .tls_options(tls_options)
+                .build()
+                .await
+                .unwrap()
+        };
+
+        // the test certificate is not trusted by default
+        let conn = connect(TlsOptions::new()).await;
+        assert!(ApicSubscription::connect_to(&conn, ws_uri.clone()).await.is_err());
+
+        // pinning it
+        let fingerprint: CertificateFingerprint = TEST_CERTIFICATE_FINGERPRINT.parse().unwrap();
+        let conn = connect(TlsOptions::new().pin_certificate("localhost", fingerprint)).await;
+        let subscription = ApicSubscription::connect_to(&conn, ws_uri.clone()).await.unwrap();
+        let events: Vec<_> = subscription.into_stream().collect().await;
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+#[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+mod native_tls_backend;
+#[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+use native_tls_backend as backend;
+#[cfg(feature = "rustls-tls")]
+mod rustls_backend;
+#[cfg(feature = "rustls-tls")]
+use rustls_backend as backend;
+#[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+compile_error!("either the \"native-tls\" or the \"rustls-tls\" feature must be enabled");
+
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::poll_fn;
+use hyper::Uri;
+use hyper::service::Service;
+use sha2::{Digest, Sha256};
+
+use crate::conn::HttpTransport;
+use crate::proxy::ProxyOptions;
+
+
+/// The SHA-256 fingerprint of a DER-encoded X.509 certificate.
+///
+/// Fingerprints can be parsed from hexadecimal strings, optionally separated by colons (as output
+/// by `openssl x509 -fingerprint -sha256`).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CertificateFingerprint([u8; 32]);
+impl CertificateFingerprint {
+    /// Creates a fingerprint from its raw bytes.
+    pub fn new(bytes: [u8; 32]) -> CertificateFingerprint {
+        CertificateFingerprint(bytes)
+    }
+
+    /// Calculates the fingerprint of the given DER-encoded certificate.
+    pub fn of_der(certificate_der: &[u8]) -> CertificateFingerprint {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&Sha256::digest(certificate_der));
+        CertificateFingerprint(bytes)
+    }
+
+    /// Returns the raw bytes of the fingerprint.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+impl fmt::Display for CertificateFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for CertificateFingerprint {
+    type Err = ParseFingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: Vec<u8> = s.bytes()
+            .filter(|b| *b != b':')
+            .collect();
+        if digits.len() != 64 {
+            return Err(ParseFingerprintError);
+        }
+
+        let mut bytes = [0u8; 32];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            let pair_str = std::str::from_utf8(pair)
+                .map_err(|_| ParseFingerprintError)?;
+            *byte = u8::from_str_radix(pair_str, 16)
+                .map_err(|_| ParseFingerprintError)?;
+        }
+        Ok(CertificateFingerprint(bytes))
+    }
+}
+
+
+/// An error that occurred while parsing a certificate fingerprint.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ParseFingerprintError;
+impl fmt::Display for ParseFingerprintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a SHA-256 fingerprint (64 hexadecimal digits, optionally separated by colons)")
+    }
+}
+impl Error for ParseFingerprintError {
+}
+
+
+/// A PEM-encoded client certificate with its private key.
+#[derive(Clone)]
+struct ClientIdentityPem {
+    certificate_pem: Vec<u8>,
+    private_key_pem: Vec<u8>,
+}
+impl fmt::Debug for ClientIdentityPem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the private key into logs
+        f.debug_struct("ClientIdentityPem")
+            .field("certificate_pem", &String::from_utf8_lossy(&self.certificate_pem))
+            .finish()
+    }
+}
+
+
+/// Options governing TLS connections to the Application Policy Infrastructure Controller (APIC).
+///
+/// The options are only validated when the transport is created using `transport`. They apply to
+/// REST API requests as well as WebSocket subscriptions.
+///
+/// The options are implemented using native-tls by default, or rustls if the `rustls-tls` feature
+/// is enabled. Note that the rustls version in use cannot verify certificates issued for IP
+/// addresses: connecting to a host addressed by IP address fails with
+/// `TlsError::UnverifiableHostName` unless its certificate is pinned (see `pin_certificate`) or
+/// certificate verification is disabled.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    ca_certificates_pem: Vec<Vec<u8>>,
+    pinned_fingerprints: HashMap<String, Vec<CertificateFingerprint>>,
+    accept_invalid_certificates: bool,
+    client_identity: Option<ClientIdentityPem>,
+}
+impl TlsOptions {
+    /// Creates a new set of TLS options which verifies certificates against the system's trusted
+    /// roots.
+    pub fn new() -> TlsOptions {
+        Default::default()
+    }
+
+    /// Additionally trusts the certificate authority with the given PEM-encoded certificate.
+    pub fn add_ca_certificate_pem(mut self, certificate_pem: &[u8]) -> Self {
+        self.ca_certificates_pem.push(Vec::from(certificate_pem));
+        self
+    }
+
+    /// Pins the certificate of the given host to the one with the given fingerprint.
+    ///
+    /// The certificate presented by a host with at least one pinned fingerprint is accepted if and
+    /// only if its fingerprint is one of the pinned ones; it is not verified against any
+    /// certificate authority, which allows pinning self-signed certificates. Hosts are matched
+    /// against the host part of the APIC URI, case-insensitively.
+    pub fn pin_certificate(mut self, host: &str, fingerprint: CertificateFingerprint) -> Self {
+        self.pinned_fingerprints
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(fingerprint);
+        self
+    }
+
+    /// Sets whether invalid certificates (e.g. self-signed or expired ones, or those issued for a
+    /// different host) are accepted.
+    ///
+    /// This makes connections vulnerable to interception and should only ever be enabled in lab
+    /// environments. A warning is logged for each connection made in this mode.
+    pub fn danger_accept_invalid_certificates(mut self, accept: bool) -> Self {
+        self.accept_invalid_certificates = accept;
+        self
+    }
+
+    /// Presents the given PEM-encoded client certificate, whose PEM-encoded private key (in PKCS#8
+    /// format) is also passed, to the APIC.
+    pub fn client_certificate_pem(mut self, certificate_pem: &[u8], private_key_pem: &[u8]) -> Self {
+        self.client_identity = Some(ClientIdentityPem {
+            certificate_pem: Vec::from(certificate_pem),
+            private_key_pem: Vec::from(private_key_pem),
+        });
+        self
+    }
+
+    /// Returns whether invalid certificates are accepted.
+    pub fn accepts_invalid_certificates(&self) -> bool {
+        self.accept_invalid_certificates
+    }
+
+    /// Returns the fingerprints pinned for the given host.
+    pub fn pinned_fingerprints(&self, host: &str) -> &[CertificateFingerprint] {
+        self.pinned_fingerprints.get(&host.to_ascii_lowercase())
+            .map(|fps| fps.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Creates an HTTP transport applying these options.
+    pub fn transport(&self) -> Result<Arc<dyn HttpTransport>, TlsError> {
+        self.transport_with(&ConnectorSettings::default())
+    }
+
+    /// Creates an HTTP transport applying these options and connecting through the given proxy.
+    pub fn proxied_transport(&self, proxy: &ProxyOptions) -> Result<Arc<dyn HttpTransport>, TlsError> {
+        self.transport_with(&ConnectorSettings {
+            proxy: Some(proxy.clone()),
+            ..Default::default()
+        })
+    }
+
+    /// Creates an HTTP transport applying these options and the given connector settings.
+    pub(crate) fn transport_with(&self, settings: &ConnectorSettings) -> Result<Arc<dyn HttpTransport>, TlsError> {
+        backend::transport(self, settings)
+    }
+
+    /// Establishes a connection to the host of the given URI applying these options and the given
+    /// connector settings, e.g. to open a WebSocket over it.
+    pub(crate) async fn connect(
+        &self,
+        settings: &ConnectorSettings,
+        uri: Uri,
+    ) -> Result<TlsStream, Box<dyn Error + Send + Sync>> {
+        let mut connector = backend::connector(self, settings)?;
+        poll_fn(|cx| connector.poll_ready(cx)).await?;
+        connector.call(uri).await
+    }
+}
+
+
+/// A connection established according to `TlsOptions`, which may or may not be encrypted depending
+/// on the scheme of the URI it has been established for.
+pub(crate) type TlsStream = backend::TlsStream;
+
+
+/// Settings for establishing connections which are independent of TLS.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectorSettings {
+    /// The proxy to connect through, if any.
+    pub proxy: Option<ProxyOptions>,
+
+    /// The maximum duration of establishing a TCP connection, if limited.
+    pub connect_timeout: Option<Duration>,
+
+    /// Whether HTTP/2 is offered during the TLS handshake.
+    pub prefer_http2: bool,
+}
+
+
+/// An error related to TLS.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A certificate authority certificate could not be loaded.
+    InvalidCaCertificate(Box<dyn Error + Send + Sync>),
+
+    /// The client certificate or its private key could not be loaded.
+    InvalidClientIdentity(Box<dyn Error + Send + Sync>),
+
+    /// The TLS implementation failed to set up a connector.
+    Backend(Box<dyn Error + Send + Sync>),
+
+    /// The certificate presented by the given host does not match any of the fingerprints pinned
+    /// for it. The fingerprint of the certificate is also passed, unless the host presented none.
+    CertificateMismatch(String, Option<CertificateFingerprint>),
+
+    /// The certificate of the given host cannot be verified because its name is not supported by
+    /// the TLS implementation (e.g. rustls cannot verify certificates for IP addresses). Pinning
+    /// its certificate avoids this problem.
+    UnverifiableHostName(String),
+}
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            TlsError::InvalidCaCertificate(e)
+                => write!(f, "invalid CA certificate: {}", e),
+            TlsError::InvalidClientIdentity(e)
+                => write!(f, "invalid client certificate or key: {}", e),
+            TlsError::Backend(e)
+                => write!(f, "failed to set up TLS: {}", e),
+            TlsError::CertificateMismatch(host, Some(fp))
+                => write!(f, "certificate of {} does not match any pinned fingerprint (it is {})", host, fp),
+            TlsError::CertificateMismatch(host, None)
+                => write!(f, "{} did not present a certificate to match against the pinned fingerprints", host),
+            TlsError::UnverifiableHostName(host)
+                => write!(f, "cannot verify certificate for host name {:?}", host),
+        }
+    }
+}
+impl Error for TlsError {
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use hyper::StatusCode;
+    use url::Url;
+
+    use crate::conn::perform_json_request;
+    use crate::test_server::{
+        self, EMPTY_RESPONSE, TEST_CA_CERTIFICATE_PEM, TEST_CERTIFICATE_FINGERPRINT, TEST_CERTIFICATE_PEM,
+    };
+
+    #[test]
+    fn parse_fingerprint() {
+        let fp: CertificateFingerprint = TEST_CERTIFICATE_FINGERPRINT.parse().unwrap();
+        assert_eq!(fp.to_string(), TEST_CERTIFICATE_FINGERPRINT);
+        assert_eq!(fp.as_bytes()[0], 0xE3);
+
+        let no_colons: String = TEST_CERTIFICATE_FINGERPRINT.chars()
+            .filter(|c| *c != ':')
+            .collect();
+        assert_eq!(no_colons.to_lowercase().parse::<CertificateFingerprint>().unwrap(), fp);
+
+        assert_eq!("E3:DE".parse::<CertificateFingerprint>(), Err(ParseFingerprintError));
+        assert_eq!("Z".repeat(64).parse::<CertificateFingerprint>(), Err(ParseFingerprintError));
+    }
+
+    async fn fetch(options: &TlsOptions, uri: &Url) -> Result<(), String> {
+        let transport = options.transport().unwrap();
+        perform_json_request(
+            &*transport,
+            uri.clone(),
+            "GET",
+            &HashMap::new(),
+            None,
+            std::time::Duration::from_secs(5),
+        ).await
+            .map(|_| ())
+            .map_err(|e| format!("{}: {:?}", e, e))
+    }
+
+    #[tokio::test]
+    async fn verification() {
+        let addr = test_server::serve_tls(|_req| (StatusCode::OK, String::from(EMPTY_RESPONSE)));
+        let uri = Url::parse(&format!("https://localhost:{}/api/class/fvTenant.json", addr.port())).unwrap();
+        let fingerprint: CertificateFingerprint = TEST_CERTIFICATE_FINGERPRINT.parse().unwrap();
+
+        // the test CA is not trusted by default
+        assert!(fetch(&TlsOptions::new(), &uri).await.is_err());
+
+        // trusting it explicitly
+        let options = TlsOptions::new()
+            .add_ca_certificate_pem(TEST_CA_CERTIFICATE_PEM.as_bytes());
+        fetch(&options, &uri).await.unwrap();
+
+        // pinning it
+        let options = TlsOptions::new()
+            .pin_certificate("LOCALHOST", fingerprint);
+        fetch(&options, &uri).await.unwrap();
+
+        // pinning a different certificate
+        let options = TlsOptions::new()
+            .pin_certificate("localhost", CertificateFingerprint::new([0u8; 32]));
+        let err = fetch(&options, &uri).await.unwrap_err();
+        assert!(err.contains("does not match any pinned fingerprint"), "{}", err);
+
+        // lab mode
+        let options = TlsOptions::new()
+            .danger_accept_invalid_certificates(true);
+        fetch(&options, &uri).await.unwrap();
+    }
+
+    #[test]
+    fn invalid_options() {
+        let options = TlsOptions::new()
+            .add_ca_certificate_pem(b"not a certificate");
+        assert!(matches!(options.transport(), Err(TlsError::InvalidCaCertificate(_))));
+
+        let options = TlsOptions::new()
+            .client_certificate_pem(TEST_CERTIFICATE_PEM.as_bytes(), b"not a key");
+        assert!(matches!(options.transport(), Err(TlsError::InvalidClientIdentity(_))));
+    }
+}
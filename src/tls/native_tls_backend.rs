@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{Body, Client, Uri};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::conn::HttpTransport;
+use crate::proxy::ProxyConnector;
+use crate::tls::{CertificateFingerprint, ConnectorSettings, TlsError, TlsOptions};
+
+
+/// Creates an HTTP transport applying the given options using native-tls.
+pub(super) fn transport(
+    options: &TlsOptions,
+    settings: &ConnectorSettings,
+) -> Result<Arc<dyn HttpTransport>, TlsError> {
+    let client = Client::builder()
+        .build::<_, Body>(connector(options, settings)?);
+    Ok(Arc::new(client))
+}
+
+/// Creates a connector establishing connections according to the given options using native-tls.
+pub(super) fn connector(
+    options: &TlsOptions,
+    settings: &ConnectorSettings,
+) -> Result<TlsConnector, TlsError> {
+    let http = ProxyConnector::new(settings.proxy.clone(), settings.connect_timeout);
+
+    let verifying = native_connector(options, options.accept_invalid_certificates, settings.prefer_http2)?;
+    let pinning = native_connector(options, true, settings.prefer_http2)?;
+    Ok(TlsConnector {
+        verifying: HttpsConnector::from((http.clone(), verifying.into())),
+        pinning: HttpsConnector::from((http, pinning.into())),
+        pinned_fingerprints: Arc::new(options.pinned_fingerprints.clone()),
+        accept_invalid_certificates: options.accept_invalid_certificates,
+    })
+}
+
+fn native_connector(
+    options: &TlsOptions,
+    accept_invalid: bool,
+    prefer_http2: bool,
+) -> Result<native_tls::TlsConnector, TlsError> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if prefer_http2 {
+        builder.request_alpns(&["h2", "http/1.1"]);
+    }
+    for ca_pem in &options.ca_certificates_pem {
+        let certificate = native_tls::Certificate::from_pem(ca_pem)
+            .map_err(|e| TlsError::InvalidCaCertificate(Box::new(e)))?;
+        builder.add_root_certificate(certificate);
+    }
+    if let Some(ci) = &options.client_identity {
+        let identity = native_tls::Identity::from_pkcs8(&ci.certificate_pem, &ci.private_key_pem)
+            .map_err(|e| TlsError::InvalidClientIdentity(Box::new(e)))?;
+        builder.identity(identity);
+    }
+    if accept_invalid {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    builder.build()
+        .map_err(|e| TlsError::Backend(Box::new(e)))
+}
+
+
+/// A connection established by `TlsConnector`.
+pub(crate) type TlsStream = NegotiatedStream;
+
+
+/// A connector establishing TLS connections according to `TlsOptions`.
+#[derive(Clone)]
+pub(super) struct TlsConnector {
+    verifying: HttpsConnector<ProxyConnector>,
+    pinning: HttpsConnector<ProxyConnector>,
+    pinned_fingerprints: Arc<HashMap<String, Vec<CertificateFingerprint>>>,
+    accept_invalid_certificates: bool,
+}
+impl Service<Uri> for TlsConnector {
+    type Response = NegotiatedStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.verifying.poll_ready(cx) {
+            Poll::Ready(Ok(())) => self.pinning.poll_ready(cx),
+            other => other,
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host()
+            .unwrap_or("")
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        let expected = match self.pinned_fingerprints.get(&host) {
+            Some(fps) => fps.clone(),
+            None => {
+                if self.accept_invalid_certificates && uri.scheme_str() == Some("https") {
+                    warn!("connecting to {} without verifying its TLS certificate", host);
+                }
+                let connecting = self.verifying.call(uri);
+                return Box::pin(async move {
+                    Ok(NegotiatedStream(connecting.await?))
+                });
+            },
+        };
+
+        let connecting = self.pinning.call(uri);
+        Box::pin(async move {
+            let stream = connecting.await?;
+            if let MaybeHttpsStream::Https(tls_stream) = &stream {
+                let fingerprint = tls_stream.get_ref()
+                    .peer_certificate()?
+                    .map(|c| c.to_der())
+                    .transpose()?
+                    .map(|der| CertificateFingerprint::of_der(&der));
+                let matches = fingerprint
+                    .map(|fp| expected.contains(&fp))
+                    .unwrap_or(false);
+                if !matches {
+                    return Err(Box::new(TlsError::CertificateMismatch(host, fingerprint)) as Self::Error);
+                }
+            }
+            Ok(NegotiatedStream(stream))
+        })
+    }
+}
+
+
+/// A stream established by `TlsConnector`, which informs hyper if HTTP/2 has been negotiated
+/// during the TLS handshake.
+pub(crate) struct NegotiatedStream(MaybeHttpsStream<TcpStream>);
+impl Connection for NegotiatedStream {
+    fn connected(&self) -> Connected {
+        let connected = self.0.connected();
+        if let MaybeHttpsStream::Https(tls_stream) = &self.0 {
+            let alpn = tls_stream.get_ref()
+                .negotiated_alpn()
+                .ok()
+                .flatten();
+            if alpn.as_deref() == Some(b"h2") {
+                return connected.negotiated_h2();
+            }
+        }
+        connected
+    }
+}
+impl AsyncRead for NegotiatedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+impl AsyncWrite for NegotiatedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
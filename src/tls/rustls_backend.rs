@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{Body, Client, Uri};
+use hyper::service::Service;
+use hyper_rustls::MaybeHttpsStream;
+use log::warn;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use rustls::internal::pemfile;
+use tokio::net::TcpStream;
+use webpki::{DNSName, DNSNameRef};
+
+use crate::conn::HttpTransport;
+use crate::proxy::ProxyConnector;
+use crate::tls::{CertificateFingerprint, ConnectorSettings, TlsError, TlsOptions};
+
+
+/// The name sent to hosts whose name is not a valid DNS name (e.g. IP addresses) if their
+/// certificates are not verified against it anyway.
+const PLACEHOLDER_DNS_NAME: &str = "apic.invalid";
+
+
+/// Creates an HTTP transport applying the given options using rustls.
+pub(super) fn transport(
+    options: &TlsOptions,
+    settings: &ConnectorSettings,
+) -> Result<Arc<dyn HttpTransport>, TlsError> {
+    let client = Client::builder()
+        .build::<_, Body>(connector(options, settings)?);
+    Ok(Arc::new(client))
+}
+
+/// Creates a connector establishing connections according to the given options using rustls.
+pub(super) fn connector(
+    options: &TlsOptions,
+    settings: &ConnectorSettings,
+) -> Result<TlsConnector, TlsError> {
+    let http = ProxyConnector::new(settings.proxy.clone(), settings.connect_timeout);
+
+    let mut config = ClientConfig::new();
+    config.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), e)) => {
+            warn!("failed to load some of the system's trusted root certificates: {}", e);
+            store
+        },
+        Err((None, e)) => return Err(TlsError::Backend(Box::new(e))),
+    };
+    for ca_pem in &options.ca_certificates_pem {
+        let (valid, invalid) = config.root_store.add_pem_file(&mut Cursor::new(ca_pem))
+            .map_err(|()| TlsError::InvalidCaCertificate("malformed PEM data".into()))?;
+        if valid == 0 || invalid > 0 {
+            return Err(TlsError::InvalidCaCertificate("no valid certificate in PEM data".into()));
+        }
+    }
+    if let Some(ci) = &options.client_identity {
+        let certificates = pemfile::certs(&mut Cursor::new(&ci.certificate_pem))
+            .map_err(|()| TlsError::InvalidClientIdentity("malformed certificate PEM data".into()))?;
+        let mut keys = pemfile::pkcs8_private_keys(&mut Cursor::new(&ci.private_key_pem))
+            .map_err(|()| TlsError::InvalidClientIdentity("malformed private key PEM data".into()))?;
+        if certificates.is_empty() || keys.len() != 1 {
+            return Err(TlsError::InvalidClientIdentity(
+                "expected a certificate and exactly one PKCS#8 private key".into()
+            ));
+        }
+        config.set_single_client_cert(certificates, keys.remove(0))
+            .map_err(|e| TlsError::InvalidClientIdentity(Box::new(e)))?;
+    }
+    if settings.prefer_http2 {
+        // hyper switches to HTTP/2 if the server selects it
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    if options.accept_invalid_certificates {
+        config.dangerous()
+            .set_certificate_verifier(Arc::new(AcceptingVerifier));
+    }
+
+    Ok(TlsConnector {
+        http,
+        config: Arc::new(config),
+        pinned_fingerprints: Arc::new(options.pinned_fingerprints.clone()),
+        accept_invalid_certificates: options.accept_invalid_certificates,
+    })
+}
+
+
+/// A certificate verifier accepting any certificate.
+struct AcceptingVerifier;
+impl ServerCertVerifier for AcceptingVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+
+/// A certificate verifier accepting only certificates with one of the given fingerprints.
+struct PinningVerifier {
+    host: String,
+    expected: Vec<CertificateFingerprint>,
+}
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let fingerprint = presented_certs.first()
+            .map(|c| CertificateFingerprint::of_der(&c.0));
+        let matches = fingerprint
+            .map(|fp| self.expected.contains(&fp))
+            .unwrap_or(false);
+        if matches {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            let err = TlsError::CertificateMismatch(self.host.clone(), fingerprint);
+            Err(TLSError::General(err.to_string()))
+        }
+    }
+}
+
+
+/// A connection established by `TlsConnector`.
+pub(crate) type TlsStream = MaybeHttpsStream<TcpStream>;
+
+
+/// A connector establishing TLS connections according to `TlsOptions`.
+#[derive(Clone)]
+pub(super) struct TlsConnector {
+    http: ProxyConnector,
+    config: Arc<ClientConfig>,
+    pinned_fingerprints: Arc<HashMap<String, Vec<CertificateFingerprint>>>,
+    accept_invalid_certificates: bool,
+}
+impl Service<Uri> for TlsConnector {
+    type Response = TlsStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let is_https = uri.scheme_str() == Some("https");
+        let host = uri.host()
+            .unwrap_or("")
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        let connecting = self.http.call(uri);
+        if !is_https {
+            return Box::pin(async move {
+                Ok(MaybeHttpsStream::Http(connecting.await?))
+            });
+        }
+
+        let (config, verifies_name) = match self.pinned_fingerprints.get(&host) {
+            Some(fps) => {
+                let mut pinning_config = (*self.config).clone();
+                pinning_config.dangerous()
+                    .set_certificate_verifier(Arc::new(PinningVerifier {
+                        host: host.clone(),
+                        expected: fps.clone(),
+                    }));
+                (Arc::new(pinning_config), false)
+            },
+            None => {
+                if self.accept_invalid_certificates {
+                    warn!("connecting to {} without verifying its TLS certificate", host);
+                }
+                (self.config.clone(), !self.accept_invalid_certificates)
+            },
+        };
+
+        Box::pin(async move {
+            let dns_name: DNSName = match DNSNameRef::try_from_ascii_str(&host) {
+                Ok(name) => name.to_owned(),
+                Err(_) if !verifies_name => DNSNameRef::try_from_ascii_str(PLACEHOLDER_DNS_NAME)
+                    .expect("placeholder is a valid DNS name")
+                    .to_owned(),
+                Err(_) => return Err(Box::new(TlsError::UnverifiableHostName(host)) as Self::Error),
+            };
+            let tcp_stream = connecting.await?;
+            let tls_stream = tokio_rustls::TlsConnector::from(config)
+                .connect(dns_name.as_ref(), tcp_stream)
+                .await?;
+            Ok(MaybeHttpsStream::Https(tls_stream))
+        })
+    }
+}
+